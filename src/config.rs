@@ -0,0 +1,563 @@
+//! Sites described by a configuration file, `avdou.toml` or `avdou.yaml`,
+//! instead of Rust code:
+//!
+//! ```toml
+//! public_dir = "public"
+//! templates = "templates"
+//!
+//! [site]
+//! title = "My site"
+//!
+//! [variables]
+//! author = "Jane Doe"
+//!
+//! [[rules]]
+//! pattern = ["content/**/*.md"]
+//! compilers = ["pandoc_markdown_shortcodes"]
+//! template = "page.html"
+//! route = ["strip_prefix:content", "nice"]
+//! permalink = true
+//!
+//! [[copies]]
+//! pattern = "static/**"
+//! route = "strip_prefix:static"
+//!
+//! [[images]]
+//! pattern = "content/**/*.jpg"
+//! route = "strip_prefix:content"
+//! widths = [480, 960, 1600]
+//! formats = ["webp", "jpeg"]
+//!
+//! [profiles.production]
+//! base_url = "https://example.org/"
+//! ```
+//!
+//! Compilers and routes are referred to by name. A route with an argument
+//! is written `name:argument`, and a list of routes is applied left to
+//! right. Rust code can add its own names to a `Registry` and pass it to
+//! `Site::from_config_with`.
+
+use anyhow::{anyhow, Context as _, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::context::Variables;
+use crate::route::{
+    compose, id_route, nice_route, pattern_route, permalink, prefix, slug_route, slugify,
+    strip_prefix, Route,
+};
+use crate::rules::{
+    expand_shortcodes_compiler, pandoc_markdown_compiler, pandoc_markdown_shortcodes_compiler,
+    scss_compiler, Filter,
+};
+use crate::shortcodes::{builtin_shortcodes, template_shortcodes, Shortcodes};
+use crate::{Copy, Document, ImageFormat, Images, Profile, RedirectFormat, Rule, Site};
+
+/// Names of the configuration files looked for by `find`, in order.
+pub const CONFIG_FILES: &[&str] = &["avdou.toml", "avdou.yaml", "avdou.yml"];
+
+/// The configuration file in `dir`, if there is one.
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    CONFIG_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+pub type CompilerFactory = Box<dyn Fn(&Site, Option<&str>) -> Result<Compiler>>;
+pub type RouteFactory = Box<dyn Fn(Option<&str>) -> Result<Route>>;
+
+/// What a compiler factory makes for a rule: the filter itself, and the
+/// paths outside the site directory it reads, which `serve` watches.
+pub struct Compiler {
+    pub filter: Filter,
+    pub watch: Vec<PathBuf>,
+}
+
+impl Compiler {
+    pub fn new(filter: Filter) -> Self {
+        Compiler {
+            filter,
+            watch: vec![],
+        }
+    }
+
+    pub fn watch(mut self, path: impl Into<PathBuf>) -> Self {
+        self.watch.push(path.into());
+        self
+    }
+}
+
+/// Compilers and routes that a configuration file can refer to by name.
+///
+/// `Registry::new` knows the compilers `pandoc_markdown`,
+/// `pandoc_markdown_shortcodes`, `scss` and `shortcodes`, and the routes
+/// `id`, `nice`, `slug`, `slugify`, `ext:<extension>`, `pattern:<pattern>`,
+/// `strip_prefix:<dir>` and `prefix:<dir>`. The shortcode compilers use the
+/// built-in shortcodes plus the templates in the site's `shortcodes/`
+/// directory. Rules using `scss` want `templating = false` and
+/// `metadata = false`, and a `!**/_*.scss` pattern to leave out partials;
+/// `scss:<dir>,<dir>` adds load paths, relative to the site directory.
+pub struct Registry {
+    compilers: HashMap<String, CompilerFactory>,
+    routes: HashMap<String, RouteFactory>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            compilers: HashMap::new(),
+            routes: HashMap::new(),
+        }
+        .compiler("pandoc_markdown", |_| Ok(pandoc_markdown_compiler()))
+        .compiler("pandoc_markdown_shortcodes", |site| {
+            Ok(pandoc_markdown_shortcodes_compiler(site_shortcodes(site)?))
+        })
+        .compiler_with("scss", |site, load_paths| {
+            let load_paths: Vec<String> = load_paths
+                .into_iter()
+                .flat_map(|paths| paths.split(','))
+                .map(|dir| {
+                    join(Path::new(&site.site_dir), dir.trim())
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect();
+            let load_path_strs: Vec<&str> = load_paths.iter().map(String::as_str).collect();
+            let mut compiler = Compiler::new(scss_compiler(&load_path_strs));
+            for path in load_paths {
+                compiler = compiler.watch(path);
+            }
+            Ok(compiler)
+        })
+        .compiler("shortcodes", |site| {
+            Ok(expand_shortcodes_compiler(site_shortcodes(site)?))
+        })
+        .route("id", id_route)
+        .route("nice", nice_route)
+        .route("slug", slug_route)
+        .route("slugify", slugify)
+        .route_with("ext", |ext| {
+            let ext = ext.to_string();
+            Ok(Box::new(move |path: &Path, _doc: &Document| {
+                Ok(path.with_extension(&ext))
+            }))
+        })
+        .route_with("pattern", |pattern| Ok(Box::new(pattern_route(pattern)?)))
+        .route_with("strip_prefix", |dir| Ok(Box::new(strip_prefix(dir))))
+        .route_with("prefix", |dir| Ok(Box::new(prefix(dir))))
+    }
+
+    /// Register a compiler. `make` is called once for every rule using it,
+    /// with the site being configured.
+    pub fn compiler(
+        mut self,
+        name: &str,
+        make: impl Fn(&Site) -> Result<Filter> + 'static,
+    ) -> Self {
+        let name_ = name.to_string();
+        self.compilers.insert(
+            name.to_string(),
+            Box::new(move |site, arg| match arg {
+                None => make(site).map(Compiler::new),
+                Some(_) => Err(anyhow!("compiler '{}' takes no argument", name_)),
+            }),
+        );
+        self
+    }
+
+    /// Register a compiler that may be written `name:argument`. `make`
+    /// gets the argument, if any, and may ask for paths to be watched.
+    pub fn compiler_with(
+        mut self,
+        name: &str,
+        make: impl Fn(&Site, Option<&str>) -> Result<Compiler> + 'static,
+    ) -> Self {
+        self.compilers.insert(name.to_string(), Box::new(make));
+        self
+    }
+
+    /// Register a route that takes no argument.
+    pub fn route(
+        mut self,
+        name: &str,
+        route: impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + Clone + 'static,
+    ) -> Self {
+        let name_ = name.to_string();
+        self.routes.insert(
+            name.to_string(),
+            Box::new(move |arg| match arg {
+                None => Ok(Box::new(route.clone()) as Route),
+                Some(_) => Err(anyhow!("route '{}' takes no argument", name_)),
+            }),
+        );
+        self
+    }
+
+    /// Register a route written `name:argument`.
+    pub fn route_with(
+        mut self,
+        name: &str,
+        make: impl Fn(&str) -> Result<Route> + 'static,
+    ) -> Self {
+        let name_ = name.to_string();
+        self.routes.insert(
+            name.to_string(),
+            Box::new(move |arg| match arg {
+                Some(arg) => make(arg),
+                None => Err(anyhow!(
+                    "route '{}' needs an argument, as in '{}:...'",
+                    name_,
+                    name_
+                )),
+            }),
+        );
+        self
+    }
+
+    fn make_compiler(&self, spec: &str, site: &Site) -> Result<Compiler> {
+        let (name, arg) = match spec.split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (spec, None),
+        };
+        let make = self
+            .compilers
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown compiler '{}'", name))?;
+        make(site, arg).with_context(|| format!("setting up compiler '{}'", name))
+    }
+
+    fn make_route(&self, specs: &[String]) -> Result<Route> {
+        let mut route: Route = Box::new(id_route);
+        for spec in specs {
+            let (name, arg) = match spec.split_once(':') {
+                Some((name, arg)) => (name, Some(arg)),
+                None => (spec.as_str(), None),
+            };
+            let make = self
+                .routes
+                .get(name)
+                .ok_or_else(|| anyhow!("unknown route '{}'", name))?;
+            route = Box::new(compose(route, make(arg)?));
+        }
+        Ok(route)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn site_shortcodes(site: &Site) -> Result<Shortcodes> {
+    let mut shortcodes = builtin_shortcodes(&site.links(), &site.image_sets());
+    let dir = Path::new(&site.site_dir).join("shortcodes");
+    if dir.is_dir() {
+        shortcodes.extend(template_shortcodes(&dir.to_string_lossy())?);
+    }
+    Ok(shortcodes)
+}
+
+/// A string or a list of strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SiteConfig {
+    site_dir: Option<String>,
+    public_dir: Option<String>,
+    base_path: Option<String>,
+    templates: Option<String>,
+    data_dir: Option<String>,
+    cache_dir: Option<String>,
+    not_found_page: Option<String>,
+    #[serde(default)]
+    site: Variables,
+    #[serde(default)]
+    variables: Variables,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+    #[serde(default)]
+    copies: Vec<CopyConfig>,
+    #[serde(default)]
+    images: Vec<ImagesConfig>,
+    #[serde(default)]
+    redirects: Vec<RedirectConfig>,
+    #[serde(default)]
+    redirect_files: Vec<String>,
+    #[serde(default)]
+    profiles: HashMap<String, ProfileConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    pattern: OneOrMany,
+    #[serde(default)]
+    compilers: Vec<String>,
+    template: Option<String>,
+    route: Option<OneOrMany>,
+    #[serde(default)]
+    permalink: bool,
+    metadata: Option<bool>,
+    templating: Option<bool>,
+    #[serde(default)]
+    minify: bool,
+    #[serde(default)]
+    watch: Vec<String>,
+    #[serde(default)]
+    variables: Variables,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CopyConfig {
+    pattern: OneOrMany,
+    route: Option<OneOrMany>,
+    #[serde(default)]
+    fingerprint: bool,
+    #[serde(default)]
+    minify: bool,
+}
+
+/// Settings of a profile; those left out keep the values of the built-in
+/// profile of the same name, if there is one.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileConfig {
+    base_url: Option<String>,
+    public_dir: Option<String>,
+    drafts: Option<bool>,
+    optimize: Option<bool>,
+    #[serde(default)]
+    site: Variables,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImagesConfig {
+    pattern: OneOrMany,
+    route: Option<OneOrMany>,
+    #[serde(default)]
+    widths: Vec<u32>,
+    #[serde(default)]
+    formats: Vec<String>,
+    quality: Option<u8>,
+    #[serde(default)]
+    fingerprint: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RedirectConfig {
+    from: String,
+    to: String,
+}
+
+impl Site {
+    /// Load a site from a configuration file, with the compilers and routes
+    /// of `Registry::new`.
+    ///
+    /// `site_dir` and `public_dir` in the file are relative to the file
+    /// itself and default to its directory and `public/` next to it. The
+    /// `watch` paths of rules are relative to the file too.
+    pub fn from_config(path: impl AsRef<Path>) -> Result<Site> {
+        Site::from_config_with(path, &Registry::new())
+    }
+
+    /// Like `from_config`, looking up compilers and routes in `registry`.
+    pub fn from_config_with(path: impl AsRef<Path>, registry: &Registry) -> Result<Site> {
+        let path = path.as_ref();
+        load(path, registry).with_context(|| format!("loading {}", path.display()))
+    }
+}
+
+fn load(path: &Path, registry: &Registry) -> Result<Site> {
+    let text = fs::read_to_string(path)?;
+    let config: SiteConfig = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&text)?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text)?,
+        _ => return Err(anyhow!("expected a .toml, .yaml or .yml file")),
+    };
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let site_dir = join(base, config.site_dir.as_deref().unwrap_or("."));
+    let public_dir = join(base, config.public_dir.as_deref().unwrap_or("public"));
+    let cache_dir = join(base, config.cache_dir.as_deref().unwrap_or(".avdou-cache"));
+
+    let mut site = Site::new()
+        .site_dir(&site_dir.to_string_lossy())
+        .public_dir(&public_dir.to_string_lossy())
+        .cache_dir(&cache_dir.to_string_lossy())
+        .not_found_page(config.not_found_page.as_deref().or(Some("404.html")))
+        .globals(config.site);
+    if let Some(base_path) = &config.base_path {
+        site = site.base_path(base_path);
+    }
+    if let Some(data_dir) = &config.data_dir {
+        site = site.data_dir(data_dir);
+    }
+    if let Some(templates) = &config.templates {
+        site = site.try_load_templates(templates)?;
+    }
+
+    for (i, rule) in config.rules.into_iter().enumerate() {
+        let r = make_rule(rule, base, &config.variables, &site, registry)
+            .with_context(|| format!("in rule #{}", i + 1))?;
+        site = site.rule(r);
+    }
+    for (i, copy) in config.copies.into_iter().enumerate() {
+        let pattern = copy.pattern.into_vec();
+        let pattern: Vec<&str> = pattern.iter().map(String::as_str).collect();
+        let route = registry
+            .make_route(&copy.route.map(OneOrMany::into_vec).unwrap_or_default())
+            .with_context(|| format!("in copy #{}", i + 1))?;
+        site = site.copy(
+            Copy::new()
+                .pattern(&pattern)
+                .route(route)
+                .fingerprint(copy.fingerprint)
+                .minify(copy.minify),
+        );
+    }
+    for (i, images) in config.images.into_iter().enumerate() {
+        let rule =
+            make_images(images, registry).with_context(|| format!("in images #{}", i + 1))?;
+        site = site.images(rule);
+    }
+
+    for redirect in &config.redirects {
+        site = site.redirect(&redirect.from, &redirect.to);
+    }
+    for format in &config.redirect_files {
+        site = site.redirect_file(match format.as_str() {
+            "netlify" => RedirectFormat::Netlify,
+            "nginx" => RedirectFormat::Nginx,
+            other => {
+                return Err(anyhow!(
+                    "unknown redirect file format '{}', expected 'netlify' or 'nginx'",
+                    other
+                ))
+            }
+        });
+    }
+
+    for (name, config) in config.profiles {
+        let mut profile = Profile::named(&name)
+            .unwrap_or_else(|| Profile::new(&name))
+            .globals(config.site);
+        if let Some(url) = &config.base_url {
+            profile = profile.base_url(url);
+        }
+        if let Some(dir) = &config.public_dir {
+            profile = profile.public_dir(&join(base, dir).to_string_lossy());
+        }
+        if let Some(drafts) = config.drafts {
+            profile = profile.drafts(drafts);
+        }
+        if let Some(optimize) = config.optimize {
+            profile = profile.optimize(optimize);
+        }
+        site = site.profile(profile);
+    }
+    Ok(site)
+}
+
+fn make_images(config: ImagesConfig, registry: &Registry) -> Result<Images> {
+    let pattern = config.pattern.into_vec();
+    let pattern: Vec<&str> = pattern.iter().map(String::as_str).collect();
+    let formats = config
+        .formats
+        .iter()
+        .map(|format| {
+            ImageFormat::from_path(Path::new(&format!("x.{}", format)))
+                .ok_or_else(|| anyhow!("unknown image format '{}'", format))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let route = registry.make_route(&config.route.map(OneOrMany::into_vec).unwrap_or_default())?;
+
+    let mut images = Images::new()
+        .pattern(&pattern)
+        .route(route)
+        .widths(&config.widths)
+        .formats(&formats)
+        .fingerprint(config.fingerprint);
+    if let Some(quality) = config.quality {
+        images = images.quality(quality);
+    }
+    Ok(images)
+}
+
+/// `base/path` without `.` components, which globwalk cannot match files
+/// under.
+fn join(base: &Path, path: &str) -> PathBuf {
+    let joined: PathBuf = base
+        .join(path)
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect();
+    if joined.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        joined
+    }
+}
+
+fn make_rule(
+    config: RuleConfig,
+    base: &Path,
+    globals: &Variables,
+    site: &Site,
+    registry: &Registry,
+) -> Result<Rule> {
+    let pattern = config.pattern.into_vec();
+    let pattern: Vec<&str> = pattern.iter().map(String::as_str).collect();
+
+    // The rule's own variables win over the site-wide ones
+    let mut context = globals.clone();
+    context.extend(config.variables);
+
+    let mut rule = Rule::new()
+        .pattern(&pattern)
+        .context(context)
+        .getmetadata(config.metadata.unwrap_or(true))
+        .templating(config.templating.unwrap_or(true))
+        .minify(config.minify);
+    for path in &config.watch {
+        rule = rule.watch(join(base, path));
+    }
+    for name in &config.compilers {
+        let compiler = registry.make_compiler(name, site)?;
+        for path in compiler.watch {
+            rule = rule.watch(path);
+        }
+        rule = rule.compiler_named(name, compiler.filter);
+    }
+    if let Some(template) = &config.template {
+        rule = rule.template(template);
+    }
+
+    let route = registry.make_route(&config.route.map(OneOrMany::into_vec).unwrap_or_default())?;
+    Ok(if config.permalink {
+        rule.route(permalink(route))
+    } else {
+        rule.route(route)
+    })
+}
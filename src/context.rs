@@ -1,14 +1,15 @@
 use anyhow::Result;
+use chrono::{Local, NaiveDateTime};
 use serde_yaml::Value;
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
-use crate::document::parse_front_matter;
+use crate::document::{is_published, parse_front_matter};
 use crate::Document;
 
 pub type Variables = HashMap<String, Value>;
-pub type Miner = Box<dyn Fn(&Document) -> Variables>;
+pub type Miner = Box<dyn Fn(&Document) -> Variables + Send + Sync>;
 
 pub trait VarsExt {
     fn var<K: Into<String>, V: Into<serde_yaml::Value>>(self, k: K, v: V) -> Self;
@@ -26,6 +27,8 @@ pub type Data = HashMap<String, Variables>;
 pub struct Mine {
     pub pattern: Vec<String>,
     pub miners: Vec<Miner>,
+    /// Whether to include drafts; `None` follows the build.
+    pub drafts: Option<bool>,
 }
 
 impl Mine {
@@ -33,9 +36,18 @@ impl Mine {
         Mine {
             pattern: vec![],
             miners: vec![],
+            drafts: None,
         }
     }
 
+    /// Whether to mine drafts, future-dated and expired documents. By
+    /// default, mines run by `Rule::mine` include them when the build does,
+    /// and `execute` leaves them out.
+    pub fn drafts(mut self, include: bool) -> Self {
+        self.drafts = Some(include);
+        self
+    }
+
     pub fn pattern(mut self, pattern: &[&str]) -> Self {
         self.pattern = pattern.iter().map(|pat| pat.to_string()).collect();
         self
@@ -43,13 +55,20 @@ impl Mine {
 
     pub fn miner<T>(mut self, f: T) -> Self
     where
-        T: Fn(&Document) -> Variables + 'static,
+        T: Fn(&Document) -> Variables + Send + Sync + 'static,
     {
         self.miners.push(Box::new(f));
         self
     }
 
     pub fn execute(&self, site_dir: &str) -> Result<Data> {
+        self.execute_at(site_dir, false, Local::now().naive_local())
+    }
+
+    /// Like `execute`, for a build at `now`, including drafts when `drafts`
+    /// is set, unless the mine chose for itself with `Mine::drafts`.
+    pub fn execute_at(&self, site_dir: &str, drafts: bool, now: NaiveDateTime) -> Result<Data> {
+        let drafts = self.drafts.unwrap_or(drafts);
         let walker = globwalk::GlobWalkerBuilder::from_patterns(site_dir, &self.pattern)
             .follow_links(true)
             .build()?
@@ -71,6 +90,9 @@ impl Mine {
                         metadata,
                     }
                 };
+                if !drafts && !is_published(&doc, now)? {
+                    continue;
+                }
 
                 let mut ctx = Variables::new();
                 for f in &self.miners {
@@ -82,3 +104,9 @@ impl Mine {
         Ok(data)
     }
 }
+
+impl Default for Mine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
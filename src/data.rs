@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Context as _, Result};
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::Path;
+
+/// Load every data file under `dir` into one tree, keyed by path: with
+/// `team/members.csv` and `nav.yaml`, templates can loop over
+/// `data.team.members` and `data.nav`.
+///
+/// YAML, TOML and JSON files are parsed as they are, with TOML dates
+/// becoming strings. A CSV file becomes a
+/// list with one mapping per row, keyed by the header row, with every cell
+/// a string. Other files are skipped, as are hidden ones.
+pub fn load_data(dir: &Path) -> Result<Value> {
+    let mut tree = Mapping::new();
+    if !dir.is_dir() {
+        return Ok(Value::Mapping(tree));
+    }
+
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let (key, value) = if path.is_dir() {
+            (name, load_data(&path)?)
+        } else {
+            let value = load_file(&path).with_context(|| format!("loading {}", path.display()))?;
+            let Some(value) = value else {
+                continue;
+            };
+            let stem = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (stem, value)
+        };
+        insert(&mut tree, key, value).with_context(|| format!("loading {}", path.display()))?;
+    }
+    Ok(Value::Mapping(tree))
+}
+
+fn load_file(path: &Path) -> Result<Option<Value>> {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if !matches!(ext.as_str(), "yaml" | "yml" | "toml" | "json" | "csv") {
+        return Ok(None);
+    }
+
+    let text = fs::read_to_string(path)?;
+    let value = match ext.as_str() {
+        "yaml" | "yml" => serde_yaml::from_str(&text)?,
+        "toml" => from_toml(toml::from_str(&text)?),
+        "json" => serde_json::from_str(&text)?,
+        _ => parse_csv(&text)?,
+    };
+    Ok(Some(value))
+}
+
+/// Convert a TOML value, turning dates and times into strings such as
+/// `1979-05-27`, as they would be written in YAML.
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::from(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::from(b),
+        toml::Value::Datetime(dt) => Value::from(dt.to_string()),
+        toml::Value::Array(items) => Value::Sequence(items.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table) => Value::Mapping(
+            table
+                .into_iter()
+                .map(|(k, v)| (Value::from(k), from_toml(v)))
+                .collect(),
+        ),
+    }
+}
+
+fn parse_csv(text: &str) -> Result<Value> {
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let headers = reader.headers()?.clone();
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let row: Mapping = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(k, v)| (Value::from(k), Value::from(v)))
+            .collect();
+        rows.push(Value::Mapping(row));
+    }
+    Ok(Value::Sequence(rows))
+}
+
+/// Add `key` to `tree`, merging it with a directory of the same name when
+/// both are mappings (`nav.yaml` next to `nav/`).
+fn insert(tree: &mut Mapping, key: String, value: Value) -> Result<()> {
+    let key = Value::from(key);
+    match (tree.get_mut(&key), value) {
+        (None, value) => {
+            tree.insert(key, value);
+        }
+        (Some(Value::Mapping(existing)), Value::Mapping(new)) => {
+            for (k, v) in new {
+                let k = k
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("non-string key {:?}", k))?;
+                insert(existing, k, v)?;
+            }
+        }
+        (Some(_), _) => {
+            return Err(anyhow!(
+                "`{}` is defined by more than one file",
+                key.as_str().unwrap()
+            ))
+        }
+    }
+    Ok(())
+}
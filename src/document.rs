@@ -1,3 +1,6 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime};
+use serde_yaml::Value;
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
@@ -10,14 +13,14 @@ pub struct Document {
 }
 
 pub fn parse_front_matter(raw: &str) -> (HashMap<String, serde_yaml::Value>, String) {
-    if let Some(striped) = raw.strip_prefix("---") {
-        if let Some(end) = striped.find("---") {
-            let meta_str = &striped[..end];
-            let body = &striped[end + 3..];
-            let metadata: HashMap<String, serde_yaml::Value> =
-                serde_yaml::from_str(meta_str).unwrap_or_default();
-            return (metadata, body.trim().to_string());
-        }
+    if let Some(striped) = raw.strip_prefix("---")
+        && let Some(end) = striped.find("---")
+    {
+        let meta_str = &striped[..end];
+        let body = &striped[end + 3..];
+        let metadata: HashMap<String, serde_yaml::Value> =
+            serde_yaml::from_str(meta_str).unwrap_or_default();
+        return (metadata, body.trim().to_string());
     }
     (HashMap::new(), raw.to_string())
 }
@@ -41,3 +44,137 @@ pub fn load_document(splitmeta: bool, path_str: String) -> Document {
         }
     }
 }
+
+/// Whether a page is published at `now`: it is not marked `draft: true`, its
+/// `date:` is not in the future and its `expires:` date, if any, has not
+/// passed. Dates that are not ISO dates are ignored.
+pub fn is_published(doc: &Document, now: NaiveDateTime) -> Result<bool> {
+    if doc.metadata.get("draft").and_then(Value::as_bool) == Some(true) {
+        return Ok(false);
+    }
+    if let Some(date) = metadata_date(doc, "date")?
+        && date > now
+    {
+        return Ok(false);
+    }
+    if let Some(expires) = metadata_date(doc, "expires")?
+        && expires <= now
+    {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// The date under `key`. Values that do not look like ISO dates, such as
+/// `March 3, 2021`, were never meant for this and count as no date, while
+/// a malformed ISO date such as `2021-13-01` is an error.
+fn metadata_date(doc: &Document, key: &str) -> Result<Option<NaiveDateTime>> {
+    let Some(text) = doc.metadata.get(key).and_then(Value::as_str) else {
+        return Ok(None);
+    };
+    match parse_date(text) {
+        Some(date) => Ok(Some(date)),
+        None if looks_like_iso_date(text) => Err(anyhow!(
+            "{} has a malformed `{}`: {:?}",
+            doc.path,
+            key,
+            text
+        )),
+        None => Ok(None),
+    }
+}
+
+fn looks_like_iso_date(text: &str) -> bool {
+    let bytes = text.trim().as_bytes();
+    bytes.len() >= 5 && bytes[..4].iter().all(u8::is_ascii_digit) && bytes[4] == b'-'
+}
+
+/// Parse a front matter date such as `2024-05-01`, `2024-05-01 14:30` or
+/// `2024-05-01T14:30:00+02:00`. Dates without an offset are in local time.
+pub fn parse_date(value: &str) -> Option<NaiveDateTime> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Some(date.with_timezone(&Local).naive_local());
+    }
+    for format in [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(date);
+        }
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_time(NaiveTime::MIN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(front_matter: &str) -> Document {
+        Document {
+            path: "post.md".to_string(),
+            content: String::new(),
+            metadata: serde_yaml::from_str(front_matter).unwrap_or_default(),
+        }
+    }
+
+    fn now() -> NaiveDateTime {
+        parse_date("2024-06-01 12:00").unwrap()
+    }
+
+    fn published(front_matter: &str) -> Result<bool> {
+        is_published(&doc(front_matter), now())
+    }
+
+    #[test]
+    fn publishes_plain_pages() {
+        assert!(published("").unwrap());
+        assert!(published("title: x\ndraft: false").unwrap());
+        assert!(published("date: 2024-06-01").unwrap());
+        assert!(published("date: 2024-06-01 12:00").unwrap());
+    }
+
+    #[test]
+    fn holds_back_drafts_future_and_expired_pages() {
+        assert!(!published("draft: true").unwrap());
+        assert!(!published("date: 2024-06-01 12:01").unwrap());
+        assert!(!published("date: 2025-01-01").unwrap());
+        assert!(!published("expires: 2024-06-01 12:00").unwrap());
+        assert!(!published("expires: 2024-01-01").unwrap());
+        assert!(published("expires: 2024-06-01T12:00:01").unwrap());
+    }
+
+    #[test]
+    fn parses_dates_with_an_offset() {
+        assert_eq!(
+            parse_date("2021-03-04T10:00:00+01:00"),
+            parse_date("2021-03-04T09:00:00Z")
+        );
+        assert!(parse_date("2021-03-04T10:00:00+01:00").is_some());
+        assert_eq!(parse_date("2021-03-04"), parse_date("2021-03-04T00:00:00"));
+    }
+
+    #[test]
+    fn ignores_dates_that_are_not_iso() {
+        assert!(published("date: March 3, 2021").unwrap());
+        assert!(published("date: 2021").unwrap());
+        assert!(published("expires: [2021]").unwrap());
+        assert!(!looks_like_iso_date("March 3, 2021"));
+        assert!(looks_like_iso_date("2021-13-01"));
+    }
+
+    #[test]
+    fn rejects_malformed_iso_dates() {
+        let err = published("date: 2021-13-01").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"post.md has a malformed `date`: "2021-13-01""#
+        );
+        assert!(published("expires: 2021-02-30").is_err());
+        assert!(published("draft: true\ndate: 2021-13-01").is_ok());
+    }
+}
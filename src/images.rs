@@ -0,0 +1,482 @@
+//! Responsive images: photos are published in several widths, and possibly
+//! other formats, next to the original.
+//!
+//! ```ignore
+//! Site::new().images(
+//!     Images::new()
+//!         .pattern(&["content/**/*.jpg"])
+//!         .route(strip_prefix("content"))
+//!         .widths(&[480, 960, 1600])
+//!         .formats(&[ImageFormat::WebP, ImageFormat::Jpeg]),
+//! )
+//! ```
+//!
+//! Templates get the variants of `photos/cat.jpg` with
+//! `image(path="photos/cat.jpg")`, and `\figure{photos/cat.jpg}` gives the
+//! browser the choice of them.
+
+use anyhow::{Context as _, Result};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::route::{check_output, id_route, resolve, source_key, url_for, Route};
+use crate::rules::{fingerprinted, write_output};
+use crate::Document;
+
+/// Formats images can be published in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    /// Lossy WebP, at the rule's quality.
+    WebP,
+}
+
+impl ImageFormat {
+    /// Format of a file, by its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+        match ext.as_str() {
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "png" => Some(ImageFormat::Png),
+            "webp" => Some(ImageFormat::WebP),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::WebP => "webp",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+            ImageFormat::WebP => "image/webp",
+        }
+    }
+}
+
+/// Copy-like rule publishing each matched image as it is, along with
+/// resized and converted variants of it.
+pub struct Images {
+    pub pattern: Vec<String>,
+    pub route: Route,
+    pub widths: Vec<u32>,
+    pub formats: Vec<ImageFormat>,
+    pub quality: u8,
+    pub fingerprint: bool,
+}
+
+impl Images {
+    pub fn new() -> Self {
+        Images {
+            pattern: vec![],
+            route: Box::new(id_route),
+            widths: vec![],
+            formats: vec![],
+            quality: 80,
+            fingerprint: false,
+        }
+    }
+
+    pub fn pattern(mut self, pattern: &[&str]) -> Self {
+        self.pattern = pattern.iter().map(|pat| pat.to_string()).collect();
+        self
+    }
+
+    pub fn route(
+        mut self,
+        router: impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static,
+    ) -> Self {
+        self.route = Box::new(router);
+        self
+    }
+
+    /// Widths to publish, in pixels. Images are never enlarged: widths
+    /// beyond the original's are skipped. Without any, the variants keep
+    /// the original size.
+    pub fn widths(mut self, widths: &[u32]) -> Self {
+        self.widths = widths.to_vec();
+        self
+    }
+
+    /// Formats to publish the variants in, the fallback for older browsers
+    /// last. Defaults to the format of the original. A format that comes
+    /// out larger than the fallback is still published, but not offered in
+    /// `sources`.
+    pub fn formats(mut self, formats: &[ImageFormat]) -> Self {
+        self.formats = formats.to_vec();
+        self
+    }
+
+    /// JPEG and WebP quality, from 1 to 100. Defaults to 80.
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Put a hash of the contents in the names of the original and of the
+    /// variants, as `Copy::fingerprint` does.
+    pub fn fingerprint(mut self, fingerprint: bool) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Publish the matched images into `output_dir` and record them in
+    /// `sets`. Encoded variants are kept in `cache_dir`, so that images
+    /// are only processed again when they change.
+    pub fn execute(
+        &self,
+        site_dir: &str,
+        output_dir: &Path,
+        base_path: &str,
+        cache_dir: &Path,
+        sets: &ImageSets,
+    ) -> Result<()> {
+        let walker = globwalk::GlobWalkerBuilder::from_patterns(site_dir, &self.pattern)
+            .follow_links(true)
+            .build()?
+            .filter_map(Result::ok);
+
+        for entry in walker {
+            let path = entry.path();
+            if path.is_file() {
+                let set = self
+                    .publish(path, site_dir, output_dir, base_path, cache_dir)
+                    .with_context(|| format!("processing image {}", path.display()))?;
+                if let Some((key, set)) = set {
+                    sets.insert(key, set);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn publish(
+        &self,
+        path: &Path,
+        site_dir: &str,
+        output_dir: &Path,
+        base_path: &str,
+        cache_dir: &Path,
+    ) -> Result<Option<(String, ImageSet)>> {
+        let Some(original_format) = ImageFormat::from_path(path) else {
+            return Ok(None);
+        };
+        let doc = Document {
+            path: path.to_str().unwrap().to_string(),
+            content: String::new(),
+            metadata: HashMap::new(),
+        };
+        let rel_out = (self.route)(path.strip_prefix(site_dir)?, &doc)?;
+        check_output(&rel_out)?;
+
+        let bytes = fs::read(path)?;
+        let published = self.published_name(&rel_out, &bytes);
+        write_output(&resolve(output_dir, &published)?, &bytes)?;
+
+        // Only decode the image when some variant is not in the cache
+        let (width, height) = image::ImageReader::new(Cursor::new(&bytes))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        let mut decoded: Option<DynamicImage> = None;
+        let hash = hex(&Sha256::digest(&bytes)[..8]);
+
+        let mut widths: Vec<u32> = self
+            .widths
+            .iter()
+            .copied()
+            .filter(|w| *w <= width)
+            .collect();
+        if widths.is_empty() {
+            widths.push(width);
+        }
+        let formats = if self.formats.is_empty() {
+            vec![original_format]
+        } else {
+            self.formats.clone()
+        };
+
+        let mut variants = vec![];
+        let mut sources = vec![];
+        for format in formats {
+            let mut srcset = vec![];
+            let mut size = 0;
+            for &w in &widths {
+                let cached = cache_dir.join("images").join(format!(
+                    "{}-{}w-q{}.{}",
+                    hash,
+                    w,
+                    self.quality,
+                    format.extension()
+                ));
+                let encoded = if cached.is_file() {
+                    fs::read(&cached)?
+                } else {
+                    if decoded.is_none() {
+                        decoded = Some(image::load_from_memory(&bytes)?);
+                    }
+                    let encoded = encode(decoded.as_ref().unwrap(), w, format, self.quality)?;
+                    write_output(&cached, &encoded)?;
+                    encoded
+                };
+
+                let stem = rel_out.file_stem().unwrap_or_default().to_string_lossy();
+                let variant =
+                    rel_out.with_file_name(format!("{}-{}w.{}", stem, w, format.extension()));
+                let variant = self.published_name(&variant, &encoded);
+                write_output(&resolve(output_dir, &variant)?, &encoded)?;
+                size += encoded.len();
+
+                let url = url_for(&variant, base_path);
+                srcset.push(format!("{} {}w", url, w));
+                variants.push(Variant {
+                    url,
+                    width: w,
+                    height: scaled_height(width, height, w),
+                    format: format.extension(),
+                });
+            }
+            sources.push((
+                ImageSource {
+                    mime_type: format.mime_type(),
+                    srcset: srcset.join(", "),
+                },
+                size,
+            ));
+        }
+
+        // Browsers take the first format they support, so one that is no
+        // smaller than the fallback would only cost them bytes
+        let fallback_size = sources.last().map_or(0, |(_, size)| *size);
+        let last = sources.len().saturating_sub(1);
+        let sources: Vec<ImageSource> = sources
+            .into_iter()
+            .enumerate()
+            .filter(|(i, (_, size))| *i == last || *size < fallback_size)
+            .map(|(_, (source, _))| source)
+            .collect();
+
+        let set = ImageSet {
+            src: url_for(&published, base_path),
+            width,
+            height,
+            srcset: sources.last().map(|s| s.srcset.clone()).unwrap_or_default(),
+            sources,
+            variants,
+        };
+        Ok(Some((source_key(&rel_out), set)))
+    }
+
+    fn published_name(&self, rel: &Path, contents: &[u8]) -> PathBuf {
+        if self.fingerprint {
+            fingerprinted(rel, contents)
+        } else {
+            rel.to_path_buf()
+        }
+    }
+}
+
+impl Default for Images {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn encode(img: &DynamicImage, width: u32, format: ImageFormat, quality: u8) -> Result<Vec<u8>> {
+    let resized = if width < img.width() {
+        img.resize(width, u32::MAX, FilterType::Lanczos3)
+    } else {
+        img.clone()
+    };
+
+    let mut out = Cursor::new(vec![]);
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality);
+            DynamicImage::ImageRgb8(resized.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        ImageFormat::Png => resized.write_to(&mut out, image::ImageFormat::Png)?,
+        // The image crate only writes lossless WebP, too large for photos
+        ImageFormat::WebP if resized.color().has_alpha() => {
+            let rgba = resized.to_rgba8();
+            let encoder = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height());
+            out.get_mut()
+                .extend_from_slice(&encoder.encode(quality as f32));
+        }
+        ImageFormat::WebP => {
+            let rgb = resized.to_rgb8();
+            let encoder = webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height());
+            out.get_mut()
+                .extend_from_slice(&encoder.encode(quality as f32));
+        }
+    }
+    Ok(out.into_inner())
+}
+
+fn scaled_height(width: u32, height: u32, new_width: u32) -> u32 {
+    ((height as u64 * new_width as u64 + width as u64 / 2) / width as u64).max(1) as u32
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A published image, as templates see it through `image(path=...)`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageSet {
+    /// URL of the original.
+    pub src: String,
+    pub width: u32,
+    pub height: u32,
+    /// `srcset` of the variants in the last format, the fallback, for an
+    /// `<img>`.
+    pub srcset: String,
+    /// One entry per format, for the `<source>`s of a `<picture>`.
+    pub sources: Vec<ImageSource>,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageSource {
+    #[serde(rename = "type")]
+    pub mime_type: &'static str,
+    pub srcset: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Variant {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: &'static str,
+}
+
+/// Shared table from the routed paths of published images to their
+/// variants, filled by `Site::build` before any page is rendered.
+#[derive(Clone, Default)]
+pub struct ImageSets(Arc<RwLock<HashMap<String, ImageSet>>>);
+
+impl ImageSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, path: &str) -> Option<ImageSet> {
+        self.0
+            .read()
+            .unwrap()
+            .get(path.trim_start_matches('/'))
+            .cloned()
+    }
+
+    pub fn insert(&self, path: String, set: ImageSet) {
+        self.0.write().unwrap().insert(path, set);
+    }
+
+    pub fn clear(&self) {
+        self.0.write().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// A site with a noisy 100×50 photo, which compresses badly as PNG.
+    fn site(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("avdou-images-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("site")).unwrap();
+        let photo = RgbImage::from_fn(100, 50, |x, y| {
+            let n = (x * 7919 + y * 104729) ^ (x * y * 31);
+            Rgb([(n % 251) as u8, (n % 241) as u8, (x * 2 + y) as u8])
+        });
+        photo.save(dir.join("site/photo.png")).unwrap();
+        dir
+    }
+
+    fn publish(dir: &Path, images: &Images) -> ImageSet {
+        let sets = ImageSets::new();
+        images
+            .execute(
+                &dir.join("site").to_string_lossy(),
+                &dir.join("public"),
+                "/",
+                &dir.join("cache"),
+                &sets,
+            )
+            .unwrap();
+        sets.get("photo.png").unwrap()
+    }
+
+    #[test]
+    fn skips_widths_beyond_the_original() {
+        let dir = site("widths");
+        let set = publish(
+            &dir,
+            &Images::new().pattern(&["*.png"]).widths(&[40, 100, 200]),
+        );
+        assert_eq!((set.width, set.height), (100, 50));
+        let widths: Vec<_> = set.variants.iter().map(|v| (v.width, v.height)).collect();
+        assert_eq!(widths, [(40, 20), (100, 50)]);
+        assert_eq!(set.srcset, "/photo-40w.png 40w, /photo-100w.png 100w");
+        assert!(dir.join("public/photo.png").is_file());
+        assert!(!dir.join("public/photo-200w.png").exists());
+
+        let set = publish(&dir, &Images::new().pattern(&["*.png"]).widths(&[400]));
+        assert_eq!(set.srcset, "/photo-100w.png 100w");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn offers_only_formats_smaller_than_the_fallback() {
+        let dir = site("formats");
+        let formats = [ImageFormat::Png, ImageFormat::WebP, ImageFormat::Jpeg];
+        let set = publish(&dir, &Images::new().pattern(&["*.png"]).formats(&formats));
+        let types: Vec<_> = set.sources.iter().map(|s| s.mime_type).collect();
+        assert_eq!(types, ["image/webp", "image/jpeg"]);
+        // The larger PNG is published all the same
+        assert!(dir.join("public/photo-100w.png").is_file());
+        assert_eq!(set.variants.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reuses_cached_variants() {
+        let dir = site("cache");
+        let images = Images::new().pattern(&["*.png"]).widths(&[40]);
+        publish(&dir, &images);
+        let cached: Vec<_> = fs::read_dir(dir.join("cache/images"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(cached.len(), 1);
+        fs::write(&cached[0], "cached").unwrap();
+
+        publish(&dir, &images);
+        assert_eq!(
+            fs::read(dir.join("public/photo-40w.png")).unwrap(),
+            b"cached"
+        );
+
+        // Another quality is another variant
+        publish(&dir, &images.quality(50));
+        assert_eq!(fs::read_dir(dir.join("cache/images")).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
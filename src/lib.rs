@@ -8,33 +8,82 @@ pub mod context;
 pub use context::{Variables, VarsExt, Mine, Miner};
 
 pub mod shortcodes;
-pub use shortcodes::{expand_shortcodes, Shortcode};
+pub use shortcodes::{
+    block_shortcode, builtin_shortcodes, expand_shortcodes, markdown, shortcode,
+    template_shortcodes, Call, Shortcode, Shortcodes,
+};
 
 pub mod route;
+pub use route::Links;
+
+pub mod data;
+
+pub mod images;
+pub use images::{ImageFormat, ImageSets, Images};
+
+pub mod minify;
+
+pub mod profile;
+pub use profile::Profile;
+
+pub mod redirects;
+pub use redirects::{Redirect, RedirectFormat};
+
+pub mod config;
+pub use config::Registry;
+
+pub mod server;
+pub use server::ServeOptions;
+use server::Files;
 
 pub mod macros;
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use chrono::Local;
 use notify::{recommended_watcher, RecursiveMode, Watcher};
 
-use std::path::{Path};
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::time::Duration;
 use std::fs::{self, Permissions};
 use std::os::unix::fs::PermissionsExt;
 pub use tera::Tera;
 use tokio::runtime::Runtime;
-use warp::hyper::Body;
 use warp::{
-    http::{StatusCode},
-    Filter, Reply,
+    http::{HeaderMap, Method},
+    Filter,
 };
 
 pub struct Site {
     pub site_dir: String,
     pub public_dir: String,
+    pub base_path: String,
     pub rules: Vec<Rule>,
     pub copies: Vec<Copy>,
+    pub images: Vec<Images>,
     pub tera: Tera,
+    pub links: Links,
+    pub assets: Links,
+    pub image_sets: ImageSets,
+    pub cache_dir: String,
+    pub redirects: Vec<Redirect>,
+    pub redirect_formats: Vec<RedirectFormat>,
+    pub not_found_page: Option<String>,
+    pub globals: Variables,
+    pub data_dir: String,
+    pub drafts: bool,
+    pub profiles: Vec<Profile>,
+    pub profile: Option<String>,
+}
+
+impl Default for Site {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Site {
@@ -42,9 +91,26 @@ impl Site {
         Self {
             site_dir: String::new(),
             public_dir: String::new(),
+            base_path: "/".to_string(),
             rules: vec![],
             copies: vec![],
+            images: vec![],
             tera: Tera::default(),
+            links: Links::new(),
+            assets: Links::new(),
+            image_sets: ImageSets::new(),
+            cache_dir: ".avdou-cache".to_string(),
+            redirects: vec![],
+            redirect_formats: vec![],
+            not_found_page: Some("404.html".to_string()),
+            globals: Variables::new(),
+            data_dir: "data".to_string(),
+            drafts: false,
+            profiles: vec![
+                Profile::named(profile::DEVELOPMENT).unwrap(),
+                Profile::named(profile::PRODUCTION).unwrap(),
+            ],
+            profile: None,
         }
     }
 
@@ -63,6 +129,116 @@ impl Site {
 	self 
     }
     
+    /// Path the site is served under, e.g. `/blog/` when it is published at
+    /// `https://example.org/blog/`. Defaults to `/`.
+    pub fn base_path(mut self, base_path: &str) -> Self {
+        self.base_path = route::normalize_base_path(base_path);
+        self
+    }
+
+    /// Directory the site is written to: the active profile's
+    /// `public_dir`, or the site's. Like `public_dir` itself, a relative path
+    /// is taken relative to the current directory, not to `site_dir`.
+    pub fn output_dir(&self) -> PathBuf {
+        let profile_dir = self.active_profile().ok().and_then(|p| p.public_dir);
+        PathBuf::from(profile_dir.unwrap_or_else(|| self.public_dir.clone()))
+    }
+
+    /// Handle to the table of published URLs, filled in by `build`.
+    pub fn links(&self) -> Links {
+        self.links.clone()
+    }
+
+    /// Handle to the table of published images, filled in by `build`.
+    pub fn image_sets(&self) -> ImageSets {
+        self.image_sets.clone()
+    }
+
+    /// Directory keeping processed images between builds. Like
+    /// `public_dir`, a relative path is taken relative to the current
+    /// directory. Defaults to `.avdou-cache`.
+    pub fn cache_dir(mut self, dir: &str) -> Self {
+        self.cache_dir = dir.to_string();
+        self
+    }
+
+    /// Permanently redirect a path of this site to another URL. Pages can
+    /// also list their old paths under `aliases:` in the front matter.
+    pub fn redirect(mut self, from: &str, to: &str) -> Self {
+        self.redirects.push(Redirect::new(from, to));
+        self
+    }
+
+    /// Also write the redirect map as a server configuration file.
+    pub fn redirect_file(mut self, format: RedirectFormat) -> Self {
+        self.redirect_formats.push(format);
+        self
+    }
+
+    /// Page `serve` answers unknown paths with, relative to the public
+    /// directory; produce it with an ordinary `Rule`. Defaults to
+    /// `404.html`, and `None` sends a plain "Not Found".
+    pub fn not_found_page(mut self, page: Option<&str>) -> Self {
+        self.not_found_page = page.map(str::to_string);
+        self
+    }
+
+    /// Variables shared by every page, available to templates as
+    /// `site.<name>`, e.g. the site title or the navigation menu. Values in
+    /// `site.yaml` in the data directory take precedence over these.
+    pub fn globals(mut self, globals: Variables) -> Self {
+        self.globals.extend(globals);
+        self
+    }
+
+    /// Directory of data files under `site_dir`, available to templates as
+    /// `data.*`; see `data::load_data`. Defaults to `data`.
+    pub fn data_dir(mut self, dir: &str) -> Self {
+        self.data_dir = dir.to_string();
+        self
+    }
+
+    /// Also publish pages marked `draft: true`, dated in the future or past
+    /// their `expires:` date. They are left out of the site, of `Links` and
+    /// of `url_for` by default.
+    pub fn drafts(mut self, include: bool) -> Self {
+        self.drafts = include;
+        self
+    }
+
+    /// Define a profile, replacing any profile of the same name, including
+    /// the built-in `development` and `production` ones.
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profiles.retain(|p| p.name != profile.name);
+        self.profiles.push(profile);
+        self
+    }
+
+    /// Build with the named profile. Without one, the `AVDOU_PROFILE`
+    /// environment variable decides, and otherwise `build` uses
+    /// `production` and `serve` uses `development`.
+    pub fn use_profile(mut self, name: &str) -> Self {
+        self.profile = Some(name.to_string());
+        self
+    }
+
+    /// Name of the profile the site is built with.
+    pub fn profile_name(&self) -> String {
+        self.profile
+            .clone()
+            .or_else(|| env::var(profile::PROFILE_ENV).ok())
+            .unwrap_or_else(|| profile::PRODUCTION.to_string())
+    }
+
+    pub fn active_profile(&self) -> Result<Profile> {
+        let name = self.profile_name();
+        self.profiles
+            .iter()
+            .find(|p| p.name == name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("unknown profile '{}'", name))
+    }
+
     pub fn rule(mut self, rule: Rule) -> Self {
         self.rules.push(rule);
         self
@@ -73,33 +249,47 @@ impl Site {
         self
     }
 
-    pub fn load_templates(mut self, dir: &str) -> Self {
+    pub fn images(mut self, images: Images) -> Self {
+        self.images.push(images);
+        self
+    }
+
+    pub fn load_templates(self, dir: &str) -> Self {
+        self.try_load_templates(dir)
+            .unwrap_or_else(|e| panic!("Failed to load templates: {:#}", e))
+    }
+
+    /// Like `load_templates`, returning an error for a missing directory or
+    /// a template that does not parse instead of panicking.
+    pub fn try_load_templates(mut self, dir: &str) -> Result<Self> {
         let mut tera = Tera::default();
-        let p = Path::new(&self.site_dir).join(dir).canonicalize().unwrap();
-
-        for entry in fs::read_dir(p).unwrap() {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() {
-                if let Some(ext) = path.extension() {
-                    if ext == "html" {
-                        let name = path.file_name().unwrap().to_string_lossy();
-                        let contents = fs::read_to_string(&path).unwrap();
-                        tera.add_raw_template(&name, &contents).unwrap();
-                    }
-                }
+        let p = Path::new(&self.site_dir).join(dir);
+        let p = p
+            .canonicalize()
+            .with_context(|| format!("template directory {}", p.display()))?;
+
+        for entry in fs::read_dir(&p)? {
+            let path = entry?.path();
+            if path.is_file()
+                && let Some(ext) = path.extension()
+                && ext == "html"
+            {
+                let name = path.file_name().unwrap().to_string_lossy();
+                let contents = fs::read_to_string(&path)?;
+                tera.add_raw_template(&name, &contents)
+                    .with_context(|| format!("loading template {}", path.display()))?;
             }
         }
         self.tera = tera;
-        self
+        Ok(self)
     }
 
     pub fn clean(&self) -> Result<()> {
-    let dir_path = Path::new(&self.public_dir);
+    let dir_path = self.output_dir();
 
     // Attempt to remove the directory and its contents
     if dir_path.exists() {
-        fs::remove_dir_all(dir_path)?;
+        fs::remove_dir_all(&dir_path)?;
         println!(
             "Directory '{}' and its contents removed successfully.",
             dir_path.display()
@@ -113,103 +303,278 @@ impl Site {
 
     
     pub fn build(&mut self) -> Result<()> {
+        let output_dir = self.output_dir();
+        self.build_to(&output_dir)
+    }
+
+    /// Build the site into `output_dir` instead of the public directory.
+    pub fn build_to(&mut self, output_dir: &Path) -> Result<()> {
+        let mut profile = self.active_profile()?;
+        let base_path = profile.base_path().unwrap_or_else(|| self.base_path.clone());
+        profile.drafts |= self.drafts;
+        // One instant for the whole build, so that links and pages agree
+        // on what is published
+        let now = Local::now().naive_local();
+
+        // Route everything first so that any page can link to any other
+        self.links.clear();
+        let mut redirects = self.redirects.clone();
         for rule in &self.rules {
-            rule.execute(&self.site_dir, &self.public_dir, &mut self.tera)?;
+            for link in rule.links(&self.site_dir, &base_path, profile.drafts, now)? {
+                for alias in &link.aliases {
+                    redirects.push(Redirect::new(alias, &link.url));
+                }
+                self.links.insert(link.source, link.url);
+            }
         }
+        let links = self.links.clone();
+        self.tera.register_function(
+            "url_for",
+            lookup_function("url_for", "no page for", move |path| {
+                links.get(path).map(tera::Value::String)
+            }),
+        );
 
+        // Stubs go first so that a real page at the same path wins
+        redirects::write_redirects(
+            &redirects,
+            &self.redirect_formats,
+            output_dir,
+            &base_path,
+        )?;
+
+        // Copy static files and images before rendering, so that pages
+        // know their fingerprinted names and the image variants
+        self.assets.clear();
         for copy in &self.copies {
-            copy.execute(&self.site_dir, &self.public_dir)?;
+            copy.execute(
+                &self.site_dir,
+                output_dir,
+                &base_path,
+                &self.assets,
+                &profile,
+            )?;
+        }
+        self.image_sets.clear();
+        for images in &self.images {
+            images.execute(
+                &self.site_dir,
+                output_dir,
+                &base_path,
+                Path::new(&self.cache_dir),
+                &self.image_sets,
+            )?;
+        }
+        let assets = self.assets.clone();
+        self.tera.register_function(
+            "asset",
+            lookup_function("asset", "no file at", move |path| {
+                assets.get(path).map(tera::Value::String)
+            }),
+        );
+
+        let image_sets = self.image_sets.clone();
+        self.tera.register_function(
+            "image",
+            lookup_function("image", "no image at", move |path| {
+                image_sets.get(path).and_then(|set| tera::to_value(set).ok())
+            }),
+        );
+
+        let mut site = self.site_variables()?;
+        site.extend(profile.globals.clone());
+        let mut globals = tera::Context::new();
+        globals.insert("site", &site);
+        globals.insert("profile", &profile.variables());
+        globals.insert(
+            "data",
+            &data::load_data(&Path::new(&self.site_dir).join(&self.data_dir))?,
+        );
+
+        for rule in &self.rules {
+            rule.execute(
+                &self.site_dir,
+                output_dir,
+                &base_path,
+                &globals,
+                &profile,
+                now,
+                &mut self.tera,
+            )?;
         }
 
         Ok(())
     }
 
+    /// `globals`, updated with `site.yaml` from the data directory when the
+    /// site has one. The file is read on every build, so `serve` picks up
+    /// changes to it.
+    fn site_variables(&self) -> Result<Variables> {
+        let mut vars = self.globals.clone();
+        let path = Path::new(&self.site_dir)
+            .join(&self.data_dir)
+            .join("site.yaml");
+        if path.is_file() {
+            let file: Variables = serde_yaml::from_str(&fs::read_to_string(&path)?)
+                .with_context(|| format!("reading {}", path.display()))?;
+            vars.extend(file);
+        }
+        Ok(vars)
+    }
+
+    /// Build-on-change development server on `127.0.0.1:port`. Blocks until
+    /// Ctrl-C.
     pub fn serve(self, port: u16) -> Result<()> {
-        let site = Arc::new(Mutex::new(self));	
-	
-        // Watch for changes
-        let site_clone = site.clone();
-        let mut watcher = recommended_watcher(move |_| {
-            let mut site = site_clone.lock().unwrap();
-            println!("Rebuilding site...");
-            let _ = site.build();
-        })?;
+        self.serve_with(ServeOptions::new(port))
+    }
 
-        // Watch the public_dir
-        let public_dir = {
-            let site = site.lock().unwrap();
-            site.public_dir.clone()
-        };
-	println!("Watching: {:?}", Path::new(&public_dir));
-	watcher.watch(Path::new(&public_dir), RecursiveMode::Recursive)?;
-	
-        // Route to serve files
-	let files = warp::path::full()
-            .and(warp::any().map(move || site.clone()))
-            .and_then(move |full_path: warp::path::FullPath, site: Arc<Mutex<Site>>| {
-                async move {
-                    // Clone public_dir for async block
-                    let public_dir = {
-                        let site = site.lock().unwrap();
-                        site.public_dir.clone()
-                    };
-
-                    // Build requested relative path
-                    let mut rel_path = full_path.as_str().trim_start_matches('/').to_string();
-                    if rel_path.is_empty() {
-                        rel_path.push_str("index.html");
-                    }
+    /// Like `serve`, with a configurable bind address and port fallback.
+    pub fn serve_with(self, options: ServeOptions) -> Result<()> {
+        Runtime::new()?.block_on(self.serve_async(options))
+    }
 
-                    let mut file_path = Path::new(&public_dir).join(&rel_path);
+    /// Run the development server inside an existing tokio runtime, until
+    /// Ctrl-C.
+    pub async fn serve_async(self, options: ServeOptions) -> Result<()> {
+        self.serve_until(options, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+    }
 
-                    // If the path is a directory, serve index.html
-                    if file_path.is_dir() {
-                        file_path = file_path.join("index.html");
-                    }
+    /// Run the development server until `shutdown` completes, then stop
+    /// watching for changes and return.
+    ///
+    /// The site is rebuilt in a background thread whenever something under
+    /// `site_dir` changes. Each rebuild goes to a staging directory that
+    /// replaces the public directory only once it is complete, so requests
+    /// are always answered from the last successful build.
+    pub async fn serve_until(
+        mut self,
+        options: ServeOptions,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        if self.profile.is_none() && env::var(profile::PROFILE_ENV).is_err() {
+            self.profile = Some(profile::DEVELOPMENT.to_string());
+        }
+        // Building can take a while; keep it off the runtime's threads
+        let (mut site, first_build) = tokio::task::spawn_blocking(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| self.build()))
+                .unwrap_or_else(|panic| Err(panic_error(panic)));
+            (self, result)
+        })
+        .await?;
+        fs::create_dir_all(site.output_dir())?;
+
+        // Compare absolute paths, which is what the watcher reports
+        let files = Files::new(site.output_dir().canonicalize()?)
+            .not_found(site.not_found_page.as_deref());
+        if let Err(e) = &first_build {
+            println!("Build failed: {:#}", e);
+            files.set_error(Some(e));
+        }
+        let site_dir = Path::new(&site.site_dir).canonicalize()?;
+
+        // Watch for changes, ignoring the server's own output
+        let (tx, rx) = mpsc::channel();
+        let mut ignored = files.output_dirs();
+        fs::create_dir_all(&site.cache_dir)?;
+        ignored.push(Path::new(&site.cache_dir).canonicalize()?);
+        let mut watcher = recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event
+                && event
+                    .paths
+                    .iter()
+                    .any(|p| !ignored.iter().any(|dir| p.starts_with(dir)))
+            {
+                let _ = tx.send(());
+            }
+        })?;
+        println!("Watching: {:?}", site_dir);
+        watcher.watch(&site_dir, RecursiveMode::Recursive)?;
+        for path in site.rules.iter().flat_map(|rule| &rule.watch) {
+            match path.canonicalize() {
+                Ok(path) if !path.starts_with(&site_dir) => {
+                    println!("Watching: {:?}", path);
+                    watcher.watch(&path, RecursiveMode::Recursive)?;
+                }
+                Ok(_) => {}
+                Err(e) => println!("Not watching {:?}: {}", path, e),
+            }
+        }
 
-                    // Return 404 if file doesn't exist
-                    if !file_path.exists() {
-                        return Ok::<_, warp::Rejection>(
-                            warp::reply::with_status("Not Found", StatusCode::NOT_FOUND)
-                                .into_response(),
-                        );
+        // Rebuild in the background; the server never touches the Site
+        let builder_files = files.clone();
+        let builder = std::thread::spawn(move || {
+            while rx.recv().is_ok() {
+                // Let a burst of events settle into a single rebuild
+                while rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+
+                println!("Rebuilding site...");
+                let staging = builder_files.staging_dir();
+                if staging.exists() {
+                    let _ = fs::remove_dir_all(&staging);
+                }
+                // A panicking route or compiler must not stop the rebuilds
+                let result = panic::catch_unwind(AssertUnwindSafe(|| site.build_to(&staging)))
+                    .unwrap_or_else(|panic| Err(panic_error(panic)))
+                    .and_then(|_| builder_files.publish(&staging));
+                match result {
+                    Ok(()) => builder_files.set_error(None),
+                    Err(e) => {
+                        println!("Build failed: {:#}", e);
+                        builder_files.set_error(Some(&e));
+                        let _ = fs::remove_dir_all(&staging);
                     }
-
-                    // Read file bytes
-                    let data = match tokio::fs::read(&file_path).await {
-                        Ok(d) => d,
-                        Err(_) => {
-                            return Ok::<_, warp::Rejection>(
-                                warp::reply::with_status("Not Found", StatusCode::NOT_FOUND)
-                                    .into_response(),
-                            )
-                        }
-                    };
-
-                    // Detect MIME type
-                    let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
-
-                    // Build response
-                    let resp = warp::http::Response::builder()
-                        .header("Content-Type", mime.as_ref())
-                        .body(Body::from(data))
-                        .unwrap();
-
-                    Ok::<_, warp::Rejection>(resp)
                 }
-            });
-
-        // Fallback 404 route
-        let not_found = warp::any().map(|| {
-            warp::reply::with_status("Not Found", StatusCode::NOT_FOUND).into_response()
+            }
         });
 
-        // Combine routes
-        let routes = files.or(not_found);
+        // Route to serve files
+        let routes = warp::path::full()
+            .and(warp::method())
+            .and(warp::header::headers_cloned())
+            .then(
+                move |full_path: warp::path::FullPath, method: Method, headers: HeaderMap| {
+                    let files = files.clone();
+                    async move { files.respond(full_path.as_str(), &method, &headers).await }
+                },
+            );
+
+        let (addr, server) = server::bind(routes, &options, shutdown)?;
+        println!("Serving at http://{}", addr);
+        server.await;
+
+        println!("Shutting down");
+        // Dropping the watcher ends the rebuild loop once a running build
+        // is done
+        drop(watcher);
+        let _ = tokio::task::spawn_blocking(move || builder.join()).await;
+        Ok(())
+    }
+}
 
-        println!("Serving at http://localhost:{}", port);
-        Runtime::new()?.block_on(warp::serve(routes).run(([127, 0, 0, 1], port)));
+fn panic_error(panic: Box<dyn std::any::Any + Send>) -> anyhow::Error {
+    let msg = panic
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown panic".to_string());
+    anyhow::anyhow!("Build panicked: {}", msg)
+}
 
-        Ok(())
-    }    
+/// Template function `name(path=...)`, giving what `lookup` finds at the
+/// path, or failing with "`name`: `missing` `path`".
+fn lookup_function(
+    name: &'static str,
+    missing: &'static str,
+    lookup: impl Fn(&str) -> Option<tera::Value> + Send + Sync + 'static,
+) -> impl tera::Function {
+    move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+        let path = args
+            .get("path")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| tera::Error::msg(format!("{}: missing `path` argument", name)))?;
+        lookup(path).ok_or_else(|| tera::Error::msg(format!("{}: {} `{}`", name, missing, path)))
+    }
 }
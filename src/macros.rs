@@ -26,11 +26,16 @@ macro_rules! template {
     };
 }
 
+/// One route, or several chained left to right with `route::compose`:
+/// `route!(strip_prefix("content"), slugify, nice_route)`.
 #[macro_export]
 macro_rules! route {
-    ( $r:expr ) => {
+    ( $r:expr $(,)? ) => {
         $r
     };
+    ( $r:expr, $($rest:expr),+ $(,)? ) => {
+        $crate::route::compose($r, $crate::route!($($rest),+))
+    };
 }
 
 #[macro_export]
@@ -73,8 +78,8 @@ macro_rules! rule_items {
         rule_items!($r; $($rest)*);
     }};
 
-    ($r:ident; route!($rt:expr) ; $($rest:tt)*) => {{
-        $r = $r.route($rt);
+    ($r:ident; route!($($rt:expr),+ $(,)?) ; $($rest:tt)*) => {{
+        $r = $r.route($crate::route!($($rt),+));
         rule_items!($r; $($rest)*);
     }};
 
@@ -105,8 +110,8 @@ macro_rules! rule_items {
         rule_items!($r; $($rest)*);
     }};
 
-    ($r:ident; route!($rt:expr) $($rest:tt)*) => {{
-        $r = $r.route($rt);
+    ($r:ident; route!($($rt:expr),+ $(,)?) $($rest:tt)*) => {{
+        $r = $r.route($crate::route!($($rt),+));
         rule_items!($r; $($rest)*);
     }};
 
@@ -136,8 +141,8 @@ macro_rules! copy_items {
     }};
 
     // --- route!(...) with semicolon ---
-    ($c:ident; route!($rt:expr); $($rest:tt)*) => {{
-        $c = $c.route($rt);
+    ($c:ident; route!($($rt:expr),+ $(,)?); $($rest:tt)*) => {{
+        $c = $c.route($crate::route!($($rt),+));
         copy_items!($c; $($rest)*);
     }};
 
@@ -148,8 +153,8 @@ macro_rules! copy_items {
     }};
 
     // --- route!(...) without semicolon ---
-    ($c:ident; route!($rt:expr) $($rest:tt)*) => {{
-        $c = $c.route($rt);
+    ($c:ident; route!($($rt:expr),+ $(,)?) $($rest:tt)*) => {{
+        $c = $c.route($crate::route!($($rt),+));
         copy_items!($c; $($rest)*);
     }};
 
@@ -0,0 +1,238 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use avdou::config;
+use avdou::route::{nice_route, permalink, strip_prefix};
+use avdou::rules::pandoc_markdown_shortcodes_compiler;
+use avdou::{builtin_shortcodes, template_shortcodes, Copy, Images, Rule, ServeOptions, Site};
+
+/// Build and preview avdou sites.
+#[derive(Parser)]
+#[command(name = "avdou", version)]
+struct Cli {
+    /// Site directory
+    #[arg(short = 'C', long = "dir", default_value = ".", global = true)]
+    dir: PathBuf,
+
+    /// Also publish drafts, future-dated and expired pages
+    #[arg(long, global = true)]
+    drafts: bool,
+
+    /// Profile to build with; defaults to $AVDOU_PROFILE, then to
+    /// `development` for serve and `production` otherwise
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build the site into public/
+    Build,
+    /// Build, then serve the site and rebuild it on changes
+    Serve {
+        #[arg(short, long, default_value_t = 8000)]
+        port: u16,
+        /// Address to listen on; 0.0.0.0 to preview from other devices
+        #[arg(short, long, default_value = "127.0.0.1")]
+        bind: IpAddr,
+    },
+    /// Remove the built site
+    Clean,
+    /// Build into a temporary directory to check for errors
+    Check,
+    /// Create a starter site
+    New { name: PathBuf },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let site = || {
+        let mut site = load_site(&cli.dir)?.drafts(cli.drafts);
+        if let Some(profile) = &cli.profile {
+            site = site.use_profile(profile);
+        }
+        anyhow::Ok(site)
+    };
+
+    match cli.command {
+        Command::Build => site()?.build(),
+        Command::Serve { port, bind } => site()?.serve_with(ServeOptions::new(port).address(bind)),
+        Command::Clean => site()?.clean(),
+        Command::Check => check(site()?),
+        Command::New { name } => new_site(&cli.dir.join(name)),
+    }
+}
+
+/// The site in `dir`, as described by its `avdou.toml` or `avdou.yaml`.
+/// Without one, the site is expected to be laid out the way `avdou new`
+/// creates it:
+///
+/// - `content/**/*.md`: Markdown pages, published as `/path/page/`
+/// - `templates/*.html`: Tera templates; pages use `page.html`
+/// - `shortcodes/`: shortcodes written as templates
+/// - `content/**/*.{jpg,png,webp}`: images, published along with smaller
+///   copies for `\figure` to offer
+/// - `static/**`: copied as is
+/// - `data/`: data files, available to templates as `data.*`; those in
+///   `data/site.yaml` also as `site.*`
+fn load_site(dir: &Path) -> Result<Site> {
+    if let Some(config) = config::find(dir) {
+        return Site::from_config(config);
+    }
+
+    let site_dir = dir.to_string_lossy().into_owned();
+    let public_dir = dir.join("public").to_string_lossy().into_owned();
+
+    let cache_dir = dir.join(".avdou-cache").to_string_lossy().into_owned();
+
+    let mut site = Site::new()
+        .site_dir(&site_dir)
+        .public_dir(&public_dir)
+        .cache_dir(&cache_dir);
+    if dir.join("templates").is_dir() {
+        site = site.try_load_templates("templates")?;
+    }
+
+    let mut shortcodes = builtin_shortcodes(&site.links(), &site.image_sets());
+    let shortcode_dir = dir.join("shortcodes");
+    if shortcode_dir.is_dir() {
+        shortcodes.extend(template_shortcodes(&shortcode_dir.to_string_lossy())?);
+    }
+
+    Ok(site
+        .rule(
+            Rule::new()
+                .pattern(&["content/**/*.md"])
+                .compiler_named(
+                    "pandoc_markdown_shortcodes",
+                    pandoc_markdown_shortcodes_compiler(shortcodes),
+                )
+                .template("page.html")
+                .minify(true)
+                .route(permalink(avdou::route!(
+                    strip_prefix("content"),
+                    nice_route
+                ))),
+        )
+        .images(
+            Images::new()
+                .pattern(&["content/**/*.{jpg,jpeg,png,webp}"])
+                .route(strip_prefix("content"))
+                .widths(&[480, 960, 1600]),
+        )
+        .copy(
+            Copy::new()
+                .pattern(&["static/**"])
+                .route(strip_prefix("static"))
+                .minify(true),
+        ))
+}
+
+fn check(mut site: Site) -> Result<()> {
+    let tmp = std::env::temp_dir().join(format!("avdou-check-{}", std::process::id()));
+    let result = site.build_to(&tmp);
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    result?;
+    println!("Site builds without errors.");
+    Ok(())
+}
+
+fn new_site(dir: &Path) -> Result<()> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(anyhow::anyhow!(
+            "'{}' already exists and is not empty",
+            dir.display()
+        ));
+    }
+
+    for (path, contents) in STARTER_FILES {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, contents)?;
+    }
+    fs::create_dir_all(dir.join("shortcodes"))?;
+
+    println!("Created a new site in {}", dir.display());
+    println!("Run `avdou -C {} serve` to preview it.", dir.display());
+    Ok(())
+}
+
+const STARTER_FILES: &[(&str, &str)] = &[
+    (
+        "content/index.md",
+        r#"---
+title: Home
+permalink: /
+---
+Welcome to your new site. Read the [first post](\ref{content/posts/hello.md}).
+"#,
+    ),
+    (
+        "content/posts/hello.md",
+        r#"---
+title: Hello, world
+date: 2024-01-01
+---
+This page was written in Markdown.
+
+\callout[kind=tip, title="Shortcodes"]
+Pages can use the built-in shortcodes, or add their own in `shortcodes/`.
+\endcallout
+"#,
+    ),
+    (
+        "content/404.md",
+        r#"---
+title: Not found
+permalink: /404.html
+---
+There is nothing here. Go back to the [home page](\ref{content/index.md}).
+"#,
+    ),
+    ("data/site.yaml", "title: My new site\n"),
+    (
+        "templates/page.html",
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>{{ title }} | {{ site.title }}</title>
+  <link rel="stylesheet" href="{{ asset(path="style.css") | safe }}">
+</head>
+<body>
+  <main>
+    <h1>{{ title }}</h1>
+    {{ content | safe }}
+  </main>
+</body>
+</html>
+"#,
+    ),
+    (
+        "static/style.css",
+        r#"body {
+  max-width: 40rem;
+  margin: 2rem auto;
+  padding: 0 1rem;
+  font-family: system-ui, sans-serif;
+  line-height: 1.6;
+}
+
+.callout {
+  border-left: 4px solid #3b82f6;
+  padding: 0.5rem 1rem;
+  background: #eff6ff;
+}
+"#,
+    ),
+    (".gitignore", "public/\n.avdou-cache/\n"),
+];
@@ -0,0 +1,41 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+/// Minify an output file by its extension: HTML pages along with their
+/// inline styles and scripts, CSS and JavaScript. Anything else is returned
+/// as is.
+pub fn minify(path: &Path, contents: Vec<u8>) -> Result<Vec<u8>> {
+    Ok(match extension(path).as_str() {
+        "html" | "htm" => minify_html(&contents),
+        "css" => minifier::css::minify(&String::from_utf8(contents)?)
+            .map_err(|e| anyhow!("minifying {}: {}", path.display(), e))?
+            .to_string()
+            .into_bytes(),
+        "js" | "mjs" => minifier::js::minify(&String::from_utf8(contents)?)
+            .to_string()
+            .into_bytes(),
+        _ => contents,
+    })
+}
+
+/// Whether `minify` changes files like `path`.
+pub fn is_minifiable(path: &Path) -> bool {
+    matches!(
+        extension(path).as_str(),
+        "html" | "htm" | "css" | "js" | "mjs"
+    )
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn minify_html(html: &[u8]) -> Vec<u8> {
+    let mut cfg = minify_html::Cfg::new();
+    cfg.keep_html_and_head_opening_tags = true;
+    cfg.minify_css = true;
+    cfg.minify_js = true;
+    minify_html::minify(html, &cfg)
+}
@@ -0,0 +1,101 @@
+use serde_yaml::Value;
+
+use crate::context::Variables;
+
+/// Environment variable selecting the profile when the site or the command
+/// line does not.
+pub const PROFILE_ENV: &str = "AVDOU_PROFILE";
+
+pub const DEVELOPMENT: &str = "development";
+pub const PRODUCTION: &str = "production";
+
+/// Named set of build settings, such as `development` for `serve` and
+/// `production` for `build`. Templates see the active one as `profile`,
+/// with its `name`, `base_url`, `drafts` and `optimize`.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+    /// Variables overriding the site's `globals`.
+    pub globals: Variables,
+    /// Absolute URL the site is published at. Its path replaces the site's
+    /// `base_path`.
+    pub base_url: Option<String>,
+    /// Directory to write the site to instead of the site's `public_dir`.
+    pub public_dir: Option<String>,
+    /// Publish drafts, future-dated and expired pages.
+    pub drafts: bool,
+    /// Run the optimizing post-processors, such as minification.
+    pub optimize: bool,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Self {
+        Profile {
+            name: name.to_string(),
+            globals: Variables::new(),
+            base_url: None,
+            public_dir: None,
+            drafts: false,
+            optimize: false,
+        }
+    }
+
+    /// The built-in profile of that name: `development` publishes drafts,
+    /// `production` optimizes.
+    pub fn named(name: &str) -> Option<Self> {
+        match name {
+            DEVELOPMENT => Some(Profile::new(DEVELOPMENT).drafts(true)),
+            PRODUCTION => Some(Profile::new(PRODUCTION).optimize(true)),
+            _ => None,
+        }
+    }
+
+    pub fn globals(mut self, globals: Variables) -> Self {
+        self.globals.extend(globals);
+        self
+    }
+
+    pub fn base_url(mut self, url: &str) -> Self {
+        self.base_url = Some(url.to_string());
+        self
+    }
+
+    pub fn public_dir(mut self, dir: &str) -> Self {
+        self.public_dir = Some(dir.to_string());
+        self
+    }
+
+    pub fn drafts(mut self, include: bool) -> Self {
+        self.drafts = include;
+        self
+    }
+
+    pub fn optimize(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Path part of `base_url`, e.g. `/blog/` for
+    /// `https://example.org/blog/`.
+    pub fn base_path(&self) -> Option<String> {
+        let url = self.base_url.as_deref()?;
+        let path = match url.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => url,
+        };
+        Some(crate::route::normalize_base_path(path))
+    }
+
+    /// What templates see as `profile`.
+    pub fn variables(&self) -> Variables {
+        let mut vars = Variables::new();
+        vars.insert("name".to_string(), Value::from(self.name.as_str()));
+        vars.insert(
+            "base_url".to_string(),
+            self.base_url.as_deref().map_or(Value::Null, Value::from),
+        );
+        vars.insert("drafts".to_string(), Value::from(self.drafts));
+        vars.insert("optimize".to_string(), Value::from(self.optimize));
+        vars
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::Result;
+use std::path::Path;
+
+use crate::route::{check_output, output_from_url, url_for};
+use crate::rules::write_output;
+
+/// A permanent redirect from an old site path to a new URL.
+#[derive(Clone, Debug)]
+pub struct Redirect {
+    /// Path on this site, relative to the base path (e.g. `/old/post/`).
+    pub from: String,
+    /// Where to send visitors; a URL on this site or elsewhere.
+    pub to: String,
+}
+
+impl Redirect {
+    pub fn new(from: &str, to: &str) -> Self {
+        Redirect {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+}
+
+/// Server configuration files that the redirect map can be written as, in
+/// addition to the HTML stubs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedirectFormat {
+    /// A `_redirects` file, as read by Netlify and Cloudflare Pages.
+    Netlify,
+    /// An nginx snippet, `redirects.nginx.conf`, to `include` in a server
+    /// block.
+    Nginx,
+}
+
+impl RedirectFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            RedirectFormat::Netlify => "_redirects",
+            RedirectFormat::Nginx => "redirects.nginx.conf",
+        }
+    }
+
+    pub fn render(&self, redirects: &[(String, String)]) -> String {
+        let mut out = String::new();
+        for (from, to) in redirects {
+            match self {
+                RedirectFormat::Netlify => out.push_str(&format!("{} {} 301\n", from, to)),
+                RedirectFormat::Nginx => out.push_str(&format!(
+                    "location = {} {{ return 301 {}; }}\n",
+                    from, to
+                )),
+            }
+        }
+        out
+    }
+}
+
+/// Small HTML page sending browsers and crawlers on to `to`.
+pub fn stub_html(to: &str) -> String {
+    let to = escape(to);
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Redirecting&hellip;</title>
+<link rel="canonical" href="{to}">
+<meta name="robots" content="noindex">
+<meta http-equiv="refresh" content="0; url={to}">
+</head>
+<body>
+<p>This page has moved to <a href="{to}">{to}</a>.</p>
+</body>
+</html>
+"#
+    )
+}
+
+/// Write a stub for every redirect, plus the requested configuration files.
+/// Stubs for paths without an extension are written as their `index.html`.
+pub fn write_redirects(
+    redirects: &[Redirect],
+    formats: &[RedirectFormat],
+    output_dir: &Path,
+    base_path: &str,
+) -> Result<()> {
+    let mut map = vec![];
+    for redirect in redirects {
+        let rel = output_from_url(&redirect.from);
+        check_output(&rel)?;
+        map.push((url_for(&rel, base_path), redirect.to.clone()));
+
+        // Servers only run the meta refresh of files they send as HTML, so
+        // `/older` gets its stub at `older/index.html`
+        let stub = if rel.extension().is_none() {
+            rel.join("index.html")
+        } else {
+            rel
+        };
+        write_output(&output_dir.join(&stub), stub_html(&redirect.to))?;
+    }
+
+    for format in formats {
+        write_output(&output_dir.join(format.file_name()), format.render(&map))?;
+    }
+    Ok(())
+}
+
+/// Escape text for use in HTML attributes and content.
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn writes_stubs_and_maps() {
+        let dir = std::env::temp_dir().join(format!("avdou-redirects-{}", std::process::id()));
+        let redirects = [
+            Redirect::new("/old/a", "/blog/new/"),
+            Redirect::new("/x.html", "https://example.org/"),
+            Redirect::new("/gone/", "/blog/"),
+        ];
+        write_redirects(&redirects, &[RedirectFormat::Netlify], &dir, "/blog/").unwrap();
+
+        let stub = fs::read_to_string(dir.join("old/a/index.html")).unwrap();
+        assert!(stub.contains(r#"url=/blog/new/""#));
+        assert!(!dir.join("old/a").is_file());
+        assert!(dir.join("x.html").is_file());
+        assert!(dir.join("gone/index.html").is_file());
+        assert_eq!(
+            fs::read_to_string(dir.join("_redirects")).unwrap(),
+            "/blog/old/a /blog/new/ 301\n\
+             /blog/x.html https://example.org/ 301\n\
+             /blog/gone/ /blog/ 301\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_aliases_outside_the_output() {
+        let dir = std::env::temp_dir().join(format!("avdou-redirects-out-{}", std::process::id()));
+        let redirects = [Redirect::new("/../escaped", "/")];
+        assert!(write_redirects(&redirects, &[], &dir, "/").is_err());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn renders_nginx_rules() {
+        let map = [("/a/".to_string(), "/b/".to_string())];
+        assert_eq!(
+            RedirectFormat::Nginx.render(&map),
+            "location = /a/ { return 301 /b/; }\n"
+        );
+    }
+}
@@ -1,35 +1,459 @@
-use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
-pub type Route = Box<dyn Fn(&Path, &str, &str) -> PathBuf + Send + Sync + 'static>;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde_yaml::Value;
 
-pub fn id_route(path: &Path, site_dir: &str, public_dir: &str) -> PathBuf {
-    let rel = path.strip_prefix(site_dir).unwrap();
-    Path::new(&site_dir)
-        .join(public_dir)
-        .join(rel)
-        .to_path_buf()
+use crate::Document;
+
+/// A route maps a source path, relative to the site directory, to an output
+/// path relative to the directory the site is written to. `Site` owns that
+/// directory and checks that routed paths stay inside it.
+pub type Route = Box<dyn Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static>;
+
+pub fn id_route(path: &Path, _doc: &Document) -> Result<PathBuf> {
+    Ok(path.to_path_buf())
 }
 
-pub fn nice_route(path: &Path, site_dir: &str, public_dir: &str) -> PathBuf {
+pub fn nice_route(path: &Path, _doc: &Document) -> Result<PathBuf> {
     let slug = path.file_stem().unwrap();
-    let rel_base = path.parent().unwrap().strip_prefix(site_dir).unwrap();
+    let rel_base = path.parent().unwrap();
 
-    Path::new(&site_dir)
-        .join(public_dir)
-        .join(rel_base)
-        .join(slug)
-        .join("index.html")
-        .to_path_buf()
+    Ok(rel_base.join(slug).join("index.html"))
 }
 
 pub fn set_extension(
     ext: &'static str,
-) -> impl Fn(&Path, &str, &str) -> PathBuf + Send + Sync + 'static {
-    move |path: &Path, site_dir: &str, public_dir: &str| {
-        let rel = path.strip_prefix(site_dir).unwrap();
-        Path::new(&site_dir)
-            .join(public_dir)
-            .join(rel)
-            .with_extension(&ext)
+) -> impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static {
+    move |path: &Path, _doc: &Document| Ok(path.with_extension(ext))
+}
+
+/// Like `nice_route`, but the last directory is taken from the `slug:` field
+/// of the front matter when there is one.
+pub fn slug_route(path: &Path, doc: &Document) -> Result<PathBuf> {
+    let rel_base = path.parent().unwrap();
+
+    Ok(rel_base.join(slug(path, doc)).join("index.html"))
+}
+
+/// Use the `permalink:` field of the front matter when present, and fall
+/// back to `route` otherwise. A permalink ending in `/` gets an
+/// `index.html`.
+pub fn permalink(
+    route: impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static,
+) -> impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static {
+    move |path: &Path, doc: &Document| match doc.metadata.get("permalink").and_then(Value::as_str) {
+        Some(link) => Ok(output_from_url(link)),
+        None => route(path, doc),
+    }
+}
+
+/// Route documents through a pattern such as `/{year}/{month}/{slug}/`.
+///
+/// `{year}`, `{month}` and `{day}` come from the `date:` field, `{slug}` from
+/// `slug:` (or the file stem), `{stem}` and `{dir}` from the source path, and
+/// any other `{key}` from the scalar front matter field of that name.
+///
+/// Fails on a malformed pattern; routing a document that lacks one of the
+/// fields the pattern uses is an error of that document.
+pub fn pattern_route(
+    pattern: &str,
+) -> Result<impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static> {
+    let parts = parse_pattern(pattern)?;
+    Ok(move |path: &Path, doc: &Document| {
+        let mut url = String::new();
+        for part in &parts {
+            match part {
+                PatternPart::Text(text) => url.push_str(text),
+                PatternPart::Placeholder(key) => url.push_str(&placeholder(key, path, doc)?),
+            }
+        }
+        Ok(output_from_url(&url))
+    })
+}
+
+/// Apply `first`, then feed its output to `second`.
+pub fn compose(
+    first: impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static,
+    second: impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static,
+) -> impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static {
+    move |path: &Path, doc: &Document| second(&first(path, doc)?, doc)
+}
+
+/// Drop a leading directory, e.g. `strip_prefix("content")` publishes
+/// `content/about.md` as `about.md`. Paths outside `dir` are left alone.
+pub fn strip_prefix(
+    dir: &str,
+) -> impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static {
+    let dir = PathBuf::from(dir);
+    move |path: &Path, _doc: &Document| Ok(path.strip_prefix(&dir).unwrap_or(path).to_path_buf())
+}
+
+/// Put the output under a subdirectory.
+pub fn prefix(dir: &str) -> impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static {
+    let dir = PathBuf::from(dir);
+    move |path: &Path, _doc: &Document| Ok(dir.join(path))
+}
+
+/// Regex replace on the `/`-separated path. `replacement` may refer to
+/// capture groups as `$1` or `${name}`. Fails on an invalid regex.
+pub fn gsub(
+    pattern: &str,
+    replacement: &str,
+) -> Result<impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static> {
+    let re = Regex::new(pattern)
+        .map_err(|e| anyhow!("invalid regex '{}' in gsub route: {}", pattern, e))?;
+    let replacement = replacement.to_string();
+    Ok(move |path: &Path, _doc: &Document| {
+        Ok(PathBuf::from(
+            re.replace_all(&source_key(path), replacement.as_str())
+                .into_owned(),
+        ))
+    })
+}
+
+/// Lowercase every path component and replace runs of anything other than
+/// letters, digits, `.`, `_` and `-` with a single `-`. Extensions are
+/// slugified apart from the name, so `My Posts/Hello World!.md` becomes
+/// `my-posts/hello-world.md`.
+pub fn slugify(path: &Path, _doc: &Document) -> Result<PathBuf> {
+    Ok(path
+        .components()
+        .map(|c| slugify_name(&c.as_os_str().to_string_lossy()))
+        .collect())
+}
+
+fn slugify_name(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => {
+            format!("{}.{}", slugify_str(stem), slugify_str(ext))
+        }
+        _ => slugify_str(name),
+    }
+}
+
+fn slugify_str(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() || c == '.' || c == '_' || c == '-' {
+            out.push(c);
+        } else if !out.ends_with('-') {
+            out.push('-');
+        }
+    }
+    out.trim_matches('-').to_string()
+}
+
+enum PatternPart {
+    Text(String),
+    Placeholder(String),
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<PatternPart>> {
+    let mut parts = vec![];
+    let mut rest = pattern;
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            parts.push(PatternPart::Text(rest[..open].to_string()));
+        }
+        let close = rest[open..]
+            .find('}')
+            .map(|close| open + close)
+            .ok_or_else(|| anyhow!("Unclosed placeholder in route pattern '{}'", pattern))?;
+        let key = &rest[open + 1..close];
+        if key.is_empty() || key.contains('{') {
+            return Err(anyhow!(
+                "Invalid placeholder '{{{}}}' in route pattern '{}'",
+                key,
+                pattern
+            ));
+        }
+        parts.push(PatternPart::Placeholder(key.to_string()));
+        rest = &rest[close + 1..];
+    }
+    if rest.contains('}') {
+        return Err(anyhow!(
+            "Unopened placeholder in route pattern '{}'",
+            pattern
+        ));
+    }
+    if !rest.is_empty() {
+        parts.push(PatternPart::Text(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+fn placeholder(key: &str, path: &Path, doc: &Document) -> Result<String> {
+    Ok(match key {
+        "year" | "month" | "day" => {
+            let date = doc
+                .metadata
+                .get("date")
+                .and_then(scalar)
+                .ok_or_else(|| anyhow!("{} has no `date` to route by", doc.path))?;
+            let mut parts = date.get(..10).unwrap_or(&date).split('-');
+            let part = match key {
+                "year" => parts.next(),
+                "month" => parts.nth(1),
+                _ => parts.nth(2),
+            };
+            part.filter(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+                .ok_or_else(|| anyhow!("{} has a malformed `date`: {}", doc.path, date))?
+                .to_string()
+        }
+        "slug" => slug(path, doc),
+        "stem" => path.file_stem().unwrap().to_string_lossy().into_owned(),
+        "dir" => source_key(path.parent().unwrap()),
+        _ => doc
+            .metadata
+            .get(key)
+            .and_then(scalar)
+            .ok_or_else(|| anyhow!("{} has no `{}` to route by", doc.path, key))?,
+    })
+}
+
+fn slug(path: &Path, doc: &Document) -> String {
+    doc.metadata
+        .get("slug")
+        .and_then(scalar)
+        .unwrap_or_else(|| path.file_stem().unwrap().to_string_lossy().into_owned())
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Output file for a URL-like path: leading slashes are dropped and a
+/// trailing slash becomes `index.html`.
+pub fn output_from_url(link: &str) -> PathBuf {
+    let rel = Path::new(link.trim_start_matches('/'));
+    if link.ends_with('/') || link.is_empty() {
+        rel.join("index.html")
+    } else {
+        rel.to_path_buf()
+    }
+}
+
+/// Check that a routed path stays inside the output directory. Routes
+/// return relative paths; anything absolute or climbing out with `..` is
+/// rejected rather than written elsewhere.
+pub fn check_output(rel: &Path) -> Result<()> {
+    for component in rel.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => {
+                return Err(anyhow!(
+                    "Route output {:?} escapes the public directory",
+                    rel
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Output path of a routed file inside `root`.
+pub fn resolve(root: &Path, rel: &Path) -> Result<PathBuf> {
+    check_output(rel)?;
+    Ok(root.join(rel))
+}
+
+/// Compute the canonical URL of an output file from its path relative to the
+/// output directory. A trailing `index.html` is dropped, so pages routed
+/// with `nice_route` get URLs like `/posts/hello/`.
+pub fn url_for(rel: &Path, base_path: &str) -> String {
+    let mut parts: Vec<String> = rel
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+
+    let is_index = parts.last().map(|s| s == "index.html").unwrap_or(false);
+    if is_index {
+        parts.pop();
+    }
+
+    let mut url = normalize_base_path(base_path);
+    url.push_str(&parts.join("/"));
+    if is_index && !parts.is_empty() {
+        url.push('/');
+    }
+    url
+}
+
+/// Turn a user supplied base path (`""`, `"blog"`, `"/blog/"`) into the
+/// form `"/blog/"`.
+pub fn normalize_base_path(base_path: &str) -> String {
+    let trimmed = base_path.trim_matches('/');
+    if trimmed.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{}/", trimmed)
+    }
+}
+
+/// Key under which a source file is recorded in `Links`: its path relative
+/// to the site directory, with `/` separators.
+pub fn source_key(rel: &Path) -> String {
+    rel.components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Where a source file is published, as recorded before rendering.
+#[derive(Clone, Debug)]
+pub struct Link {
+    /// Source path relative to the site directory.
+    pub source: String,
+    pub url: String,
+    /// Old URLs listed under `aliases:` in the front matter.
+    pub aliases: Vec<String>,
+}
+
+/// Shared table from source files to the URLs they are published under.
+///
+/// `Site::build` fills it before any page is rendered, so pages can link to
+/// each other regardless of the order of the rules. `Site::assets` is
+/// another such table, from the routed paths of copied files to their
+/// possibly fingerprinted URLs.
+#[derive(Clone, Default)]
+pub struct Links(Arc<RwLock<HashMap<String, String>>>);
+
+impl Links {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, source: &str) -> Option<String> {
+        self.0.read().unwrap().get(source.trim_start_matches('/')).cloned()
+    }
+
+    pub fn insert(&self, source: String, url: String) {
+        self.0.write().unwrap().insert(source, url);
+    }
+
+    pub fn clear(&self) {
+        self.0.write().unwrap().clear();
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, String> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(front_matter: &str) -> Document {
+        Document {
+            path: "content/post.md".to_string(),
+            content: String::new(),
+            metadata: serde_yaml::from_str(front_matter).unwrap_or_default(),
+        }
+    }
+
+    fn route(pattern: &str, front_matter: &str) -> Result<PathBuf> {
+        pattern_route(pattern)?(Path::new("posts/hello.md"), &doc(front_matter))
+    }
+
+    #[test]
+    fn routes_through_patterns() {
+        let front_matter = "date: 2021-03-04T10:00:00+01:00\nlang: en\nn: 7";
+        assert_eq!(
+            route("/{lang}/{year}/{month}/{day}/{stem}-{n}/", front_matter).unwrap(),
+            Path::new("en/2021/03/04/hello-7/index.html")
+        );
+        assert_eq!(
+            route("{dir}/{slug}.html", "slug: hi").unwrap(),
+            Path::new("posts/hi.html")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        for pattern in ["/{year", "/{}/", "/{a{b}/", "/year}/"] {
+            assert!(pattern_route(pattern).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn reports_missing_and_malformed_fields() {
+        let err = route("/{lang}/", "").unwrap_err();
+        assert_eq!(err.to_string(), "content/post.md has no `lang` to route by");
+        assert!(route("/{year}/", "").is_err());
+        assert!(route("/{year}/", "date: March 3, 2021").is_err());
+        assert!(route("/{day}/", "date: 2021-03").is_err());
+        assert!(route("/{month}/", "date: 2021--04").is_err());
+        assert!(route("/{lang}/", "lang: [en, fr]").is_err());
+    }
+
+    #[test]
+    fn slugifies_names_and_extensions_apart() {
+        let slug = |path: &str| slugify(Path::new(path), &doc("")).unwrap();
+        assert_eq!(
+            slug("My Posts/Hello World!.md"),
+            Path::new("my-posts/hello-world.md")
+        );
+        assert_eq!(slug("a/Über Straße.MD"), Path::new("a/über-straße.md"));
+        assert_eq!(slug(".Hidden File"), Path::new(".hidden-file"));
+        assert_eq!(slug("v1.2 notes.md"), Path::new("v1.2-notes.md"));
+        // `..` is left for `check_output` to reject
+        assert_eq!(slug("a/../B c"), Path::new("a/../b-c"));
+    }
+
+    #[test]
+    fn replaces_with_gsub() {
+        let route = gsub(r"^posts/(\d+)-(.*)$", "$1/$2").unwrap();
+        assert_eq!(
+            route(Path::new("posts/12-x.md"), &doc("")).unwrap(),
+            Path::new("12/x.md")
+        );
+        assert!(gsub("(", "").is_err());
+    }
+
+    #[test]
+    fn maps_urls_to_output_files() {
+        assert_eq!(output_from_url("/a/b/"), Path::new("a/b/index.html"));
+        assert_eq!(output_from_url("/a/b.html"), Path::new("a/b.html"));
+        assert_eq!(output_from_url("/"), Path::new("index.html"));
+        assert_eq!(output_from_url(""), Path::new("index.html"));
+        assert_eq!(output_from_url("//x"), Path::new("x"));
+    }
+
+    #[test]
+    fn confines_outputs() {
+        assert!(check_output(Path::new("a/./b.html")).is_ok());
+        assert!(check_output(Path::new("../a.html")).is_err());
+        assert!(check_output(Path::new("a/../../b")).is_err());
+        assert!(check_output(Path::new("/etc/passwd")).is_err());
+        assert!(resolve(Path::new("public"), Path::new("../x")).is_err());
+        assert_eq!(
+            resolve(Path::new("public"), Path::new("a/b")).unwrap(),
+            Path::new("public/a/b")
+        );
+    }
+
+    #[test]
+    fn computes_urls() {
+        assert_eq!(url_for(Path::new("index.html"), ""), "/");
+        assert_eq!(url_for(Path::new("index.html"), "/blog"), "/blog/");
+        assert_eq!(url_for(Path::new("a/index.html"), "blog/"), "/blog/a/");
+        assert_eq!(url_for(Path::new("a/b.html"), "/"), "/a/b.html");
+        assert_eq!(url_for(Path::new("./a/x.css"), "/blog/"), "/blog/a/x.css");
+        assert_eq!(normalize_base_path("//"), "/");
     }
 }
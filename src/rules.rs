@@ -1,13 +1,20 @@
-use super::context::Variables;
-use super::document::{load_document, Document};
-use super::route::{id_route, Route};
-use super::shortcodes::{expand_shortcodes, Shortcode};
+use super::context::{Mine, Variables};
+use super::document::{is_published, load_document, Document};
+use super::minify::{is_minifiable, minify};
+use super::profile::Profile;
+use super::route::{
+    check_output, id_route, resolve, set_extension, source_key, url_for, Link, Links, Route,
+};
+use super::shortcodes::{expand_shortcodes, protect_shortcodes, restore_shortcodes, Shortcodes};
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use chrono::NaiveDateTime;
 use pandoc::{
     InputFormat, InputKind, MarkdownExtension, OutputFormat, OutputKind, Pandoc, PandocOption,
     PandocOutput,
 };
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fs::{self, Permissions};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
@@ -19,10 +26,17 @@ pub type Filter = Box<dyn Fn(Document) -> anyhow::Result<Document> + Send + Sync
 pub struct Rule {
     pub pattern: Vec<String>,
     pub filters: Vec<Filter>,
+    /// Names of the compilers in `filters`, for error messages.
+    pub filter_names: Vec<Option<String>>,
     pub context: Variables,
+    /// Collections gathered at build time, by the variable they are put in.
+    pub mines: Vec<(String, Mine)>,
     pub template: Option<String>,
     pub route: Route,
     pub splitmeta: bool,
+    pub templating: bool,
+    pub watch: Vec<PathBuf>,
+    pub minify: bool,
 }
 
 impl Rule {
@@ -30,10 +44,15 @@ impl Rule {
         Self {
             pattern: Vec::new(),
             filters: vec![],
+            filter_names: vec![],
             context: Variables::new(),
+            mines: vec![],
             template: None,
             route: Box::new(id_route),
             splitmeta: true,
+            templating: true,
+            watch: vec![],
+            minify: false,
         }
     }
 
@@ -44,6 +63,14 @@ impl Rule {
 
     pub fn compiler(mut self, filter: Filter) -> Self {
         self.filters.push(Box::new(filter));
+        self.filter_names.push(None);
+        self
+    }
+
+    /// Like `compiler`, naming it in the errors it reports.
+    pub fn compiler_named(mut self, name: &str, filter: Filter) -> Self {
+        self.filters.push(Box::new(filter));
+        self.filter_names.push(Some(name.to_string()));
         self
     }
 
@@ -52,6 +79,14 @@ impl Rule {
         self
     }
 
+    /// Gather `mine` at every build and give it to templates as `key`.
+    /// Unless the mine says otherwise, it includes drafts exactly when the
+    /// build does, so collections and feeds list the pages being published.
+    pub fn mine(mut self, key: &str, mine: Mine) -> Self {
+        self.mines.push((key.to_string(), mine));
+        self
+    }
+
     pub fn template(mut self, template: &str) -> Self {
         self.template = Some(template.to_string());
         self
@@ -59,7 +94,7 @@ impl Rule {
 
     pub fn route(
         mut self,
-        router: impl Fn(&Path, &str, &str) -> PathBuf + Send + Sync + 'static,
+        router: impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static,
     ) -> Self {
         self.route = Box::new(router);
         self
@@ -70,65 +105,185 @@ impl Rule {
         self
     }
 
-    pub fn execute(&self, site_dir: &str, public_dir: &str, tera: &mut Tera) -> Result<()> {
+    /// Render each source as a Tera template before compiling it. On by
+    /// default; turn it off for sources that are not meant for Tera, such
+    /// as stylesheets, where `{#` opens a Tera comment.
+    pub fn templating(mut self, templating: bool) -> Self {
+        self.templating = templating;
+        self
+    }
+
+    /// Also rebuild on changes under `path` when serving, for sources that
+    /// read files outside the site directory.
+    pub fn watch(mut self, path: impl Into<PathBuf>) -> Self {
+        self.watch.push(path.into());
+        self
+    }
+
+    /// Minify the rendered HTML, CSS or JavaScript, according to the
+    /// output's extension, in profiles that optimize.
+    pub fn minify(mut self, minify: bool) -> Self {
+        self.minify = minify;
+        self
+    }
+
+    /// Source files matched by the rule's patterns.
+    pub fn sources(&self, site_dir: &str) -> Result<Vec<PathBuf>> {
         let walker = globwalk::GlobWalkerBuilder::from_patterns(site_dir, &self.pattern)
             .follow_links(true)
             .build()?
             .filter_map(Result::ok);
 
-        for entry in walker {
-            let path = entry.path();
-            if path.is_file() {
-                let path_str = path.to_str().unwrap().to_string();
-                // Load document
-                let mut doc = load_document(self.splitmeta, path_str);
-
-                // Build context
-                let mut ctx = Context::new();
-                for (k, v) in self.context.iter() {
-                    ctx.insert(k, v);
-                }
-                for (k, v) in &doc.metadata {
-                    ctx.insert(k, v);
-                }
-
-                // Apply template to markdown NEED TO ADD CONTEXT: ADD CONTEXT IN avdou_site THAT IS USED HERE!
-
-                tera.add_raw_template(&doc.path, &doc.content).unwrap();
-                let md = tera.render(&doc.path, &ctx)?;
-                doc.content = md;
+        Ok(walker
+            .map(|entry| entry.path().to_path_buf())
+            .filter(|path| path.is_file())
+            .collect())
+    }
 
-                // Apply filters
-                for f in &self.filters {
-                    doc = f(doc)?;
-                }
+    /// Documents to publish at `now`: the matched files, less the drafts,
+    /// future-dated and expired ones unless `drafts` is set.
+    pub fn documents(
+        &self,
+        site_dir: &str,
+        drafts: bool,
+        now: NaiveDateTime,
+    ) -> Result<Vec<(PathBuf, Document)>> {
+        let mut docs = vec![];
+        for path in self.sources(site_dir)? {
+            let doc = load_document(self.splitmeta, path.to_str().unwrap().to_string());
+            if drafts || is_published(&doc, now)? {
+                docs.push((path, doc));
+            }
+        }
+        Ok(docs)
+    }
 
-                // Apply template
-                if let Some(template_name) = &self.template {
-                    ctx.insert("content", &doc.content);
-                    let html = tera.render(template_name, &ctx)?;
-                    doc.content = html;
-                }
+    /// Where each file published at `now` will go, without rendering
+    /// anything.
+    pub fn links(
+        &self,
+        site_dir: &str,
+        base_path: &str,
+        drafts: bool,
+        now: NaiveDateTime,
+    ) -> Result<Vec<Link>> {
+        let mut links = vec![];
+        for (path, doc) in self.documents(site_dir, drafts, now)? {
+            let rel = path.strip_prefix(site_dir)?;
+            let rel_out =
+                (self.route)(rel, &doc).with_context(|| format!("routing {}", doc.path))?;
+            check_output(&rel_out)?;
+            let aliases = match doc.metadata.get("aliases") {
+                Some(serde_yaml::Value::String(alias)) => vec![alias.clone()],
+                Some(serde_yaml::Value::Sequence(seq)) => seq
+                    .iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect(),
+                _ => vec![],
+            };
+            links.push(Link {
+                source: source_key(rel),
+                url: url_for(&rel_out, base_path),
+                aliases,
+            });
+        }
+        Ok(links)
+    }
 
-                // Determine output path
+    /// Render every file published at `now` into `output_dir`, with the
+    /// settings of `profile`. Templates see the variables of `globals`, then the rule's
+    /// context and mines, then the front matter, each overriding the
+    /// previous ones.
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute(
+        &self,
+        site_dir: &str,
+        output_dir: &Path,
+        base_path: &str,
+        globals: &Context,
+        profile: &Profile,
+        now: NaiveDateTime,
+        tera: &mut Tera,
+    ) -> Result<()> {
+        let mut mined = Context::new();
+        for (key, mine) in &self.mines {
+            let data = mine
+                .execute_at(site_dir, profile.drafts, now)
+                .with_context(|| format!("gathering `{}`", key))?;
+            mined.insert(key, &data);
+        }
 
-                let f = &self.route;
-                let final_path = f(path, site_dir, public_dir);
-                if let Some(parent) = final_path.parent() {
-                    fs::create_dir_all(parent).expect("Failed to create directories");
-                    fs::set_permissions(parent, Permissions::from_mode(0o755))?;
-                }
+        for (path, mut doc) in self.documents(site_dir, profile.drafts, now)? {
+            let path = path.as_path();
+
+            // Determine output path
+            let f = &self.route;
+            let rel_out = f(path.strip_prefix(site_dir)?, &doc)
+                .with_context(|| format!("routing {}", doc.path))?;
+            let final_path =
+                resolve(output_dir, &rel_out).with_context(|| format!("routing {}", doc.path))?;
+
+            // Build context
+            let mut ctx = globals.clone();
+            for (k, v) in self.context.iter() {
+                ctx.insert(k, v);
+            }
+            ctx.extend(mined.clone());
+            for (k, v) in &doc.metadata {
+                ctx.insert(k, v);
+            }
+            ctx.insert("url", &url_for(&rel_out, base_path));
 
-                fs::write(&final_path, doc.content)?;
+            // Apply template to markdown NEED TO ADD CONTEXT: ADD CONTEXT IN avdou_site THAT IS USED HERE!
+
+            if self.templating {
+                let md = tera
+                    .add_raw_template(&doc.path, &doc.content)
+                    .and_then(|_| tera.render(&doc.path, &ctx))
+                    .with_context(|| format!("rendering {} as a Tera template", doc.path))?;
+                doc.content = md;
+            }
+
+            // Apply filters
+            for (i, f) in self.filters.iter().enumerate() {
+                let path = doc.path.clone();
+                doc = f(doc).with_context(|| match self.filter_names.get(i) {
+                    Some(Some(name)) => format!("compiler '{}' failed on {}", name, path),
+                    _ => format!("compiler #{} failed on {}", i + 1, path),
+                })?;
+            }
+
+            // Apply template
+            if let Some(template_name) = &self.template {
+                ctx.insert("content", &doc.content);
+                let html = tera.render(template_name, &ctx).with_context(|| {
+                    format!("applying template '{}' to {}", template_name, doc.path)
+                })?;
+                doc.content = html;
             }
+
+            let mut output = doc.content.into_bytes();
+            if self.minify && profile.optimize {
+                output = minify(&final_path, output)?;
+            }
+            write_output(&final_path, output)
+                .with_context(|| format!("writing {}", final_path.display()))?;
         }
         Ok(())
     }
 }
 
+impl Default for Rule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Copy {
     pub pattern: Vec<String>,
     pub route: Route,
+    pub fingerprint: bool,
+    pub minify: bool,
 }
 
 impl Copy {
@@ -136,6 +291,8 @@ impl Copy {
         Copy {
             pattern: vec![],
             route: Box::new(id_route),
+            fingerprint: false,
+            minify: false,
         }
     }
 
@@ -146,13 +303,38 @@ impl Copy {
 
     pub fn route(
         mut self,
-        router: impl Fn(&Path, &str, &str) -> PathBuf + Send + Sync + 'static,
+        router: impl Fn(&Path, &Document) -> Result<PathBuf> + Send + Sync + 'static,
     ) -> Self {
         self.route = Box::new(router);
         self
     }
 
-    pub fn execute(&self, site_dir: &str, public_dir: &str) -> Result<()> {
+    /// Put a hash of the contents in the name of every copied file, so
+    /// `css/style.css` is published as `css/style.3fa9c1d2.css` and can be
+    /// cached for good. Templates find the current name with
+    /// `asset(path="css/style.css")`.
+    pub fn fingerprint(mut self, fingerprint: bool) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Minify the copied HTML, CSS and JavaScript files, by extension, in
+    /// profiles that optimize. Other files are copied as they are.
+    pub fn minify(mut self, minify: bool) -> Self {
+        self.minify = minify;
+        self
+    }
+
+    /// Copy the matched files into `output_dir`, recording in `assets` the
+    /// URL each routed path is published under.
+    pub fn execute(
+        &self,
+        site_dir: &str,
+        output_dir: &Path,
+        base_path: &str,
+        assets: &Links,
+        profile: &Profile,
+    ) -> Result<()> {
         let walker = globwalk::GlobWalkerBuilder::from_patterns(site_dir, &self.pattern)
             .follow_links(true)
             .build()?
@@ -161,27 +343,86 @@ impl Copy {
         for entry in walker {
             let path = entry.path();
             if path.is_file() {
+                // Static files are not parsed, so routes only see their path
+                let doc = Document {
+                    path: path.to_str().unwrap().to_string(),
+                    content: String::new(),
+                    metadata: HashMap::new(),
+                };
                 let f = &self.route;
-                let final_path = f(path, site_dir, public_dir);
-
-                if let Some(parent) = final_path.parent() {
-                    fs::create_dir_all(parent).expect("Failed to create directories");
-                    fs::set_permissions(parent, Permissions::from_mode(0o755))?;
-                }
-                fs::copy(path, &final_path)?;
+                let rel_out = f(path.strip_prefix(site_dir)?, &doc)
+                    .with_context(|| format!("routing {}", doc.path))?;
+                check_output(&rel_out)?;
+
+                // Files are copied as they are unless their contents change
+                let minifying = self.minify && profile.optimize && is_minifiable(&rel_out);
+                let published = if self.fingerprint || minifying {
+                    let mut contents = fs::read(path)?;
+                    if minifying {
+                        contents = minify(&rel_out, contents)?;
+                    }
+                    let published = if self.fingerprint {
+                        fingerprinted(&rel_out, &contents)
+                    } else {
+                        rel_out.clone()
+                    };
+                    write_output(&resolve(output_dir, &published)?, contents)?;
+                    published
+                } else {
+                    let final_path = resolve(output_dir, &rel_out)?;
+                    if let Some(parent) = final_path.parent() {
+                        fs::create_dir_all(parent)?;
+                        fs::set_permissions(parent, Permissions::from_mode(0o755))?;
+                    }
+                    fs::copy(path, &final_path)?;
+                    rel_out.clone()
+                };
+                assets.insert(source_key(&rel_out), url_for(&published, base_path));
             }
         }
         Ok(())
     }
 }
 
+impl Default for Copy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `path` with a short hash of `contents` before its extension.
+pub(crate) fn fingerprinted(path: &Path, contents: &[u8]) -> PathBuf {
+    let hash: String = Sha256::digest(contents)[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, hash, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, hash),
+    };
+    path.with_file_name(name)
+}
+
+/// Write an output file, creating its parent directories.
+pub(crate) fn write_output(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+        fs::set_permissions(parent, Permissions::from_mode(0o755))?;
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
+
 pub fn pandoc_markdown_compiler() -> Filter {
     Box::new(move |doc: Document| pandoc_markdown_to_html(doc))
 }
 
-pub fn expand_shortcodes_compiler(handlers: Vec<Shortcode>) -> Filter {
+/// Expand `handlers` in the document. Shortcodes written as Tera templates
+/// can be mixed in with `shortcodes::template_shortcodes`.
+pub fn expand_shortcodes_compiler(handlers: Shortcodes) -> Filter {
     Box::new(move |doc: Document| {
-        let expanded = expand_shortcodes(&doc.content, &handlers);
+        let expanded = expand_shortcodes(&doc.content, &handlers, &doc)?;
         Ok(Document {
             content: expanded,
             ..doc
@@ -189,6 +430,100 @@ pub fn expand_shortcodes_compiler(handlers: Vec<Shortcode>) -> Filter {
     })
 }
 
+/// Expand shortcodes and compile the page with pandoc. Shortcodes producing
+/// Markdown are expanded before compiling; those producing HTML are kept
+/// out of pandoc's way and put back afterwards.
+pub fn pandoc_markdown_shortcodes_compiler(handlers: Shortcodes) -> Filter {
+    Box::new(move |doc: Document| {
+        let (markdown, protected) = protect_shortcodes(&doc.content, &handlers, &doc)?;
+        let html = pandoc_markdown_to_html(Document {
+            content: markdown,
+            ..doc
+        })?;
+        Ok(Document {
+            content: restore_shortcodes(&html.content, &protected),
+            ..html
+        })
+    })
+}
+
+/// Compile Sass stylesheets to CSS: SCSS, or the indented syntax for
+/// `.sass` files. `@use` and `@import` are resolved relative to the file
+/// they appear in, stylesheet or partial, then in `load_paths`.
+pub fn scss_compiler(load_paths: &[&str]) -> Filter {
+    let load_paths: Vec<PathBuf> = load_paths.iter().map(PathBuf::from).collect();
+    Box::new(move |doc: Document| {
+        let path = Path::new(&doc.path);
+        let syntax = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("sass") => grass::InputSyntax::Sass,
+            _ => grass::InputSyntax::Scss,
+        };
+        let fs = SassFs {
+            entry: path,
+            content: &doc.content,
+        };
+        let options = grass::Options::default()
+            .fs(&fs)
+            .input_syntax(syntax)
+            .load_paths(&load_paths);
+        let css = grass::from_path(path, &options).map_err(|e| anyhow::anyhow!("{}", e))?;
+        Ok(Document {
+            content: css,
+            ..doc
+        })
+    })
+}
+
+/// The file system as the Sass compiler sees it: the stylesheet being
+/// compiled reads as the document, which may have been through Tera, and
+/// anything it imports comes from disk.
+#[derive(Debug)]
+struct SassFs<'a> {
+    entry: &'a Path,
+    content: &'a str,
+}
+
+impl grass::Fs for SassFs<'_> {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path == self.entry || path.is_file()
+    }
+
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        if path == self.entry {
+            Ok(self.content.as_bytes().to_vec())
+        } else {
+            fs::read(path)
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+}
+
+/// Rule publishing Sass stylesheets as CSS, e.g. `scss/main.scss` as
+/// `scss/main.css`; change the route to put them elsewhere. Partials, whose
+/// names start with `_`, are only imported. `serve` also rebuilds on
+/// changes under `load_paths`.
+pub fn scss_rule(pattern: &[&str], load_paths: &[&str]) -> Rule {
+    let mut patterns = pattern.to_vec();
+    patterns.extend(["!**/_*.scss", "!**/_*.sass"]);
+    let mut rule = Rule::new()
+        .pattern(&patterns)
+        .getmetadata(false)
+        .templating(false)
+        .compiler_named("scss", scss_compiler(load_paths))
+        .route(set_extension("css"));
+    for path in load_paths {
+        rule = rule.watch(path);
+    }
+    rule
+}
+
 fn pandoc_markdown_to_html(doc: Document) -> Result<Document> {
     let mut pandoc = Pandoc::new();
 
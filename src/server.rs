@@ -0,0 +1,656 @@
+use std::fs;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio_util::io::ReaderStream;
+use warp::http::{HeaderMap, Method, Response, StatusCode};
+use warp::hyper::Body;
+use warp::{Filter, Reply};
+
+use crate::redirects::escape;
+
+/// Where the development server listens.
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    pub address: IpAddr,
+    pub port: u16,
+    /// How many following ports to try when `port` is taken.
+    pub port_attempts: u16,
+}
+
+impl ServeOptions {
+    /// Listen on `127.0.0.1:port`, moving on to the next ports (up to 10)
+    /// when it is in use.
+    pub fn new(port: u16) -> Self {
+        ServeOptions {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            port_attempts: 10,
+        }
+    }
+
+    /// Bind address; `0.0.0.0` makes the preview reachable from other
+    /// devices on the network.
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Fail instead of trying other ports when `port` is taken.
+    pub fn exact_port(mut self) -> Self {
+        self.port_attempts = 0;
+        self
+    }
+}
+
+/// Bind `filter` to the first free port allowed by `options`. The returned
+/// future serves requests until `shutdown` completes.
+pub fn bind<F>(
+    filter: F,
+    options: &ServeOptions,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<(SocketAddr, impl Future<Output = ()>)>
+where
+    F: Filter + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    // Each bind attempt consumes its shutdown signal, so the caller's is
+    // broadcast to whichever attempt succeeds
+    let (tx, rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        shutdown.await;
+        let _ = tx.send(true);
+    });
+
+    let mut last_error = None;
+    for port in options.port..=options.port.saturating_add(options.port_attempts) {
+        let addr = SocketAddr::new(options.address, port);
+        let mut rx = rx.clone();
+        let signal = async move {
+            let _ = rx.changed().await;
+        };
+        match warp::serve(filter.clone()).try_bind_with_graceful_shutdown(addr, signal) {
+            Ok(bound) => return Ok(bound),
+            Err(e) => {
+                println!("Cannot listen on {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(anyhow::anyhow!(
+        "No free port from {}: {}",
+        options.port,
+        last_error.unwrap()
+    ))
+}
+
+/// The files the development server answers from.
+///
+/// Rebuilds are written to `staging_dir` and moved into place by
+/// `publish`. The lock is only held while files are looked up and opened,
+/// and while the directories are renamed, so requests always see a whole
+/// build and are never held up by one in progress.
+#[derive(Clone, Debug)]
+pub struct Files {
+    pub root: PathBuf,
+    /// Page sent with `404 Not Found`, relative to `root`.
+    pub not_found: Option<String>,
+    swap: Arc<RwLock<()>>,
+    /// The last build error, shown over every HTML page until a build
+    /// succeeds.
+    error: Arc<RwLock<Option<Vec<String>>>>,
+}
+
+impl Files {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Files {
+            root: root.into(),
+            not_found: Some("404.html".to_string()),
+            swap: Arc::new(RwLock::new(())),
+            error: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Record the outcome of a build: an error is overlaid on HTML
+    /// responses, `None` clears it.
+    pub fn set_error(&self, error: Option<&anyhow::Error>) {
+        let lines = error.map(|e| e.chain().map(|cause| cause.to_string()).collect());
+        *self.error.write().unwrap() = lines;
+    }
+
+    /// Where the next build is written, next to `root` so that it can be
+    /// renamed into place.
+    pub fn staging_dir(&self) -> PathBuf {
+        self.sibling("staging")
+    }
+
+    fn previous_dir(&self) -> PathBuf {
+        self.sibling("previous")
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let name = self.root.file_name().unwrap_or_default().to_string_lossy();
+        self.root
+            .with_file_name(format!(".{}.{}", name.trim_start_matches('.'), suffix))
+    }
+
+    /// Directories the server writes to, so that a watcher can ignore
+    /// changes inside them.
+    pub fn output_dirs(&self) -> Vec<PathBuf> {
+        vec![self.root.clone(), self.staging_dir(), self.previous_dir()]
+    }
+
+    /// Replace `root` with the completed build in `staging`. When that
+    /// fails, the last build stays in place.
+    pub fn publish(&self, staging: &Path) -> anyhow::Result<()> {
+        let previous = self.previous_dir();
+        if previous.exists() {
+            fs::remove_dir_all(&previous)?;
+        }
+        {
+            let _guard = self.swap.write().unwrap();
+            let moved = self.root.exists();
+            if moved {
+                fs::rename(&self.root, &previous)?;
+            }
+            if let Err(e) = fs::rename(staging, &self.root) {
+                if moved {
+                    fs::rename(&previous, &self.root)?;
+                }
+                return Err(e.into());
+            }
+        }
+        // Responses still streaming from the old build keep their open
+        // files. The new build is already live, so failing to clean up the
+        // old one is not a build failure; the next publish tries again.
+        if let Err(e) = fs::remove_dir_all(&previous) {
+            println!("Cannot remove {}: {}", previous.display(), e);
+        }
+        Ok(())
+    }
+
+    pub fn not_found(mut self, page: Option<&str>) -> Self {
+        self.not_found = page.map(str::to_string);
+        self
+    }
+
+    /// Answer a `GET` or `HEAD` request, honouring conditional requests
+    /// (`If-None-Match`, `If-Modified-Since`) and single byte ranges. File
+    /// contents are streamed rather than read into memory, and requests can
+    /// never reach outside `root`.
+    pub async fn respond(
+        &self,
+        request_path: &str,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> Response<Body> {
+        if method != Method::GET && method != Method::HEAD {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header("Allow", "GET, HEAD")
+                .body(Body::empty())
+                .unwrap();
+        }
+
+        let found = {
+            let _guard = self.swap.read().unwrap();
+            match find_file(&self.root, request_path) {
+                Some(path) => fs::File::open(&path).ok().map(|file| (path, file)),
+                None => None,
+            }
+        };
+
+        let error = self.error.read().unwrap().clone();
+        match (found, error) {
+            (Some((path, file)), Some(error)) if is_html(&path) => {
+                with_overlay(file, &error, method)
+            }
+            (Some((path, file)), _) => {
+                serve_file(&path, tokio::fs::File::from_std(file), method, headers).await
+            }
+            (None, _) => self.not_found_response(method),
+        }
+    }
+
+    fn not_found_response(&self, method: &Method) -> Response<Body> {
+        let body = {
+            let _guard = self.swap.read().unwrap();
+            self.not_found
+                .as_deref()
+                .and_then(|page| find_file(&self.root, page))
+                .and_then(|page| fs::read(page).ok())
+        };
+
+        let builder = Response::builder().status(StatusCode::NOT_FOUND);
+        let error = self.error.read().unwrap().clone();
+        let (content_type, body) = match (body, error) {
+            (Some(body), Some(error)) => ("text/html; charset=utf-8", inject_overlay(body, &error)),
+            (None, Some(error)) => (
+                "text/html; charset=utf-8",
+                inject_overlay(b"<h1>Not Found</h1>".to_vec(), &error),
+            ),
+            (Some(body), None) => ("text/html; charset=utf-8", body),
+            (None, None) => ("text/plain; charset=utf-8", b"Not Found".to_vec()),
+        };
+        let builder = builder
+            .header("Content-Type", content_type)
+            .header("Content-Length", body.len());
+        if method == Method::HEAD {
+            builder.body(Body::empty()).unwrap()
+        } else {
+            builder.body(Body::from(body)).unwrap()
+        }
+    }
+}
+
+async fn serve_file(
+    file_path: &Path,
+    mut file: tokio::fs::File,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let meta = match file.metadata().await {
+        Ok(meta) => meta,
+        Err(_) => return not_found(),
+    };
+
+    let len = meta.len();
+    let modified = meta.modified().unwrap_or(UNIX_EPOCH);
+    let etag = etag(len, modified);
+    let last_modified = httpdate::fmt_http_date(modified);
+    let mime = mime_guess::from_path(file_path).first_or_octet_stream();
+
+    let builder = Response::builder()
+        .header("ETag", &etag)
+        .header("Last-Modified", &last_modified)
+        .header("Cache-Control", "no-cache")
+        .header("Accept-Ranges", "bytes");
+
+    if not_modified(headers, &etag, modified) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    let range = match requested_range(headers, &etag, len) {
+        Ok(range) => range,
+        Err(()) => {
+            return builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{}", len))
+                .body(Body::empty())
+                .unwrap();
+        }
+    };
+
+    let (status, start, count, builder) = match range {
+        Some((start, end)) => (
+            StatusCode::PARTIAL_CONTENT,
+            start,
+            end - start + 1,
+            builder.header("Content-Range", format!("bytes {}-{}/{}", start, end, len)),
+        ),
+        None => (StatusCode::OK, 0, len, builder),
+    };
+
+    let builder = builder
+        .status(status)
+        .header("Content-Type", mime.as_ref())
+        .header("Content-Length", count);
+
+    if method == Method::HEAD {
+        return builder.body(Body::empty()).unwrap();
+    }
+
+    if start > 0 && file.seek(SeekFrom::Start(start)).await.is_err() {
+        return not_found();
+    }
+    let stream = ReaderStream::new(file.take(count));
+    builder.body(Body::wrap_stream(stream)).unwrap()
+}
+
+/// The file a request path refers to, serving `index.html` for directories.
+///
+/// The path is percent-decoded and any `..` segment is refused. The result
+/// is canonicalized and must still lie inside `root`, so symlinks cannot be
+/// used to escape it either.
+fn find_file(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path.split(['?', '#']).next()?)?;
+
+    let mut file_path = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            s if s.contains('\\') || s.contains('\0') => return None,
+            s => file_path.push(s),
+        }
+    }
+
+    if file_path.is_dir() {
+        file_path = file_path.join("index.html");
+    }
+
+    let root = root.canonicalize().ok()?;
+    let file_path = file_path.canonicalize().ok()?;
+    (file_path.starts_with(&root) && file_path.is_file()).then_some(file_path)
+}
+
+/// Decode `%XX` escapes, refusing malformed ones and invalid UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn is_html(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("html") | Some("htm")
+    )
+}
+
+/// An HTML page with the build error overlaid. It is never cached, so the
+/// plain page comes back as soon as the error is fixed.
+fn with_overlay(mut file: fs::File, error: &[String], method: &Method) -> Response<Body> {
+    let mut html = vec![];
+    if std::io::Read::read_to_end(&mut file, &mut html).is_err() {
+        return not_found();
+    }
+    let body = inject_overlay(html, error);
+    let builder = Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Content-Length", body.len())
+        .header("Cache-Control", "no-store");
+    if method == Method::HEAD {
+        builder.body(Body::empty()).unwrap()
+    } else {
+        builder.body(Body::from(body)).unwrap()
+    }
+}
+
+/// Insert the error overlay before `</body>`, or at the end of the page.
+fn inject_overlay(html: Vec<u8>, error: &[String]) -> Vec<u8> {
+    let overlay = overlay_html(error);
+    let mut html = String::from_utf8_lossy(&html).into_owned();
+    match html.rfind("</body>") {
+        Some(i) => html.insert_str(i, &overlay),
+        None => html.push_str(&overlay),
+    }
+    html.into_bytes()
+}
+
+fn overlay_html(error: &[String]) -> String {
+    let (what, details) = match error.split_first() {
+        Some((what, details)) => (what.as_str(), details),
+        None => ("Build failed", &[][..]),
+    };
+    let details: String = details
+        .iter()
+        .map(|line| format!("<li>{}</li>", escape(line)))
+        .collect();
+    format!(
+        r#"<div id="avdou-error-overlay" style="position:fixed;inset:0;z-index:2147483647;overflow:auto;background:rgba(20,20,20,.92);color:#eee;font:14px/1.5 ui-monospace,monospace;padding:2em">
+<h2 style="color:#ff6b6b;margin-top:0">Build failed</h2>
+<p style="font-size:16px">{}</p>
+<ul style="color:#ffd479;white-space:pre-wrap">{}</ul>
+<p style="color:#999">Showing the last successful build. This message goes away once the site builds again.</p>
+</div>"#,
+        escape(what),
+        details
+    )
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(Body::from("Not Found"))
+        .unwrap()
+}
+
+fn etag(len: u64, modified: SystemTime) -> String {
+    let nanos = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", len, nanos)
+}
+
+/// Whether a conditional request can be answered with `304 Not Modified`.
+/// `If-None-Match` takes precedence over `If-Modified-Since`.
+fn not_modified(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if let Some(inm) = headers.get("If-None-Match").and_then(|v| v.to_str().ok()) {
+        return inm
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+
+    if let Some(since) = headers
+        .get("If-Modified-Since")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+    {
+        // HTTP dates have a resolution of one second
+        let modified_secs = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let since_secs = since
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        return modified_secs <= since_secs;
+    }
+
+    false
+}
+
+/// The inclusive byte range asked for with `Range`, if any. Multiple ranges
+/// and ranges made stale by `If-Range` fall back to the whole file; `Err`
+/// means the range cannot be satisfied.
+fn requested_range(headers: &HeaderMap, etag: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = match headers.get("Range").and_then(|v| v.to_str().ok()) {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+    if let Some(if_range) = headers.get("If-Range").and_then(|v| v.to_str().ok())
+        && if_range.trim() != etag
+    {
+        return Ok(None);
+    }
+
+    let ranges = match spec.trim().strip_prefix("bytes=") {
+        Some(ranges) if !ranges.contains(',') => ranges,
+        _ => return Ok(None),
+    };
+    let (start, end) = ranges.split_once('-').ok_or(())?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // suffix range: the last `end` bytes
+        let suffix: u64 = end.parse().map_err(|_| ())?;
+        if suffix == 0 || len == 0 {
+            return Err(());
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().map_err(|_| ())?;
+        let end: u64 = if end.is_empty() {
+            len.saturating_sub(1)
+        } else {
+            end.parse::<u64>()
+                .map_err(|_| ())?
+                .min(len.saturating_sub(1))
+        };
+        if start >= len || start > end {
+            return Err(());
+        }
+        (start, end)
+    };
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn publish_keeps_the_last_build_on_failure() {
+        let dir = std::env::temp_dir().join(format!("avdou-publish-{}", std::process::id()));
+        let files = Files::new(dir.join("public"));
+        fs::create_dir_all(&files.root).unwrap();
+        fs::write(files.root.join("index.html"), "old").unwrap();
+
+        assert!(files.publish(&dir.join("missing")).is_err());
+        assert_eq!(
+            fs::read_to_string(files.root.join("index.html")).unwrap(),
+            "old"
+        );
+
+        let staging = files.staging_dir();
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(staging.join("index.html"), "new").unwrap();
+        files.publish(&staging).unwrap();
+        assert_eq!(
+            fs::read_to_string(files.root.join("index.html")).unwrap(),
+            "new"
+        );
+        assert!(!staging.exists() && !files.previous_dir().exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("/a%20b/%C3%A9").as_deref(), Some("/a b/é"));
+        assert_eq!(percent_decode("/plain").as_deref(), Some("/plain"));
+        assert_eq!(percent_decode("/bad%2"), None);
+        assert_eq!(percent_decode("/bad%zz"), None);
+        assert_eq!(percent_decode("/%ff"), None);
+    }
+
+    #[test]
+    fn confines_files_to_the_root() {
+        let dir = std::env::temp_dir().join(format!("avdou-server-{}", std::process::id()));
+        let root = dir.join("public");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("index.html"), "home").unwrap();
+        fs::write(root.join("sub/index.html"), "sub").unwrap();
+        fs::write(root.join("a b.txt"), "space").unwrap();
+        fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("link.txt")).unwrap();
+
+        let found = |path: &str| find_file(&root, path).map(|p| fs::read_to_string(p).unwrap());
+        assert_eq!(found("/").as_deref(), Some("home"));
+        assert_eq!(found("/sub").as_deref(), Some("sub"));
+        assert_eq!(found("/sub/?q=1#top").as_deref(), Some("sub"));
+        assert_eq!(found("/a%20b.txt").as_deref(), Some("space"));
+        assert_eq!(found("/missing"), None);
+        assert_eq!(found("/../secret.txt"), None);
+        assert_eq!(found("/sub/%2e%2e/%2e%2e/secret.txt"), None);
+        assert_eq!(found("/..%5csecret.txt"), None);
+        assert_eq!(found("/link.txt"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let tag = etag(10, modified);
+        let date = httpdate::fmt_http_date(modified);
+        let earlier = httpdate::fmt_http_date(modified - Duration::from_secs(1));
+
+        assert!(!not_modified(&HeaderMap::new(), &tag, modified));
+        assert!(not_modified(
+            &headers(&[("If-None-Match", &tag)]),
+            &tag,
+            modified
+        ));
+        assert!(not_modified(
+            &headers(&[("If-None-Match", &format!("\"other\", W/{}", tag))]),
+            &tag,
+            modified
+        ));
+        assert!(not_modified(
+            &headers(&[("If-None-Match", "*")]),
+            &tag,
+            modified
+        ));
+        assert!(!not_modified(
+            &headers(&[("If-None-Match", "\"other\"")]),
+            &tag,
+            modified
+        ));
+        assert!(not_modified(
+            &headers(&[("If-Modified-Since", &date)]),
+            &tag,
+            modified
+        ));
+        assert!(!not_modified(
+            &headers(&[("If-Modified-Since", &earlier)]),
+            &tag,
+            modified
+        ));
+        // If-None-Match wins over If-Modified-Since
+        assert!(!not_modified(
+            &headers(&[("If-None-Match", "\"other\""), ("If-Modified-Since", &date)]),
+            &tag,
+            modified
+        ));
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        let range = |spec: &str| requested_range(&headers(&[("Range", spec)]), "\"t\"", 100);
+        assert_eq!(requested_range(&HeaderMap::new(), "\"t\"", 100), Ok(None));
+        assert_eq!(range("bytes=0-9"), Ok(Some((0, 9))));
+        assert_eq!(range("bytes=90-"), Ok(Some((90, 99))));
+        assert_eq!(range("bytes=-10"), Ok(Some((90, 99))));
+        assert_eq!(range("bytes=-500"), Ok(Some((0, 99))));
+        assert_eq!(range("bytes=50-500"), Ok(Some((50, 99))));
+        assert_eq!(range("bytes=0-1,5-6"), Ok(None));
+        assert_eq!(range("items=0-1"), Ok(None));
+        assert_eq!(range("bytes=100-"), Err(()));
+        assert_eq!(range("bytes=9-5"), Err(()));
+        assert_eq!(range("bytes=-0"), Err(()));
+        assert_eq!(range("bytes=x-1"), Err(()));
+
+        let if_range = |tag: &str| {
+            requested_range(
+                &headers(&[("Range", "bytes=0-9"), ("If-Range", tag)]),
+                "\"t\"",
+                100,
+            )
+        };
+        assert_eq!(if_range("\"t\""), Ok(Some((0, 9))));
+        assert_eq!(if_range("\"stale\""), Ok(None));
+    }
+}
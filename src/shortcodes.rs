@@ -1,76 +1,857 @@
-use anyhow::Result;
-
-#[derive(Copy, Clone, Debug)]
-pub struct Shortcode {
-    pub tag: &'static str,
-    pub render: fn(&[String]) -> String,
-}
-
-pub fn expand_shortcodes(input: &str, handlers: &[Shortcode]) -> String {
-    let mut output = String::new();
-    let chars: Vec<char> = input.chars().collect();
-    let mut i = 0;
-
-    while i < chars.len() {
-        if chars[i] == '\\' {
-            // look for a backslash introducing a shortcode
-            let start = i;
-            let mut matched = None;
-
-            for handler in handlers {
-                let tag_chars: Vec<char> = handler.tag.chars().collect();
-                if chars[start + 1..].starts_with(&tag_chars) {
-                    matched = Some(handler);
-                    break;
-                }
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tera::{Context, Tera};
+
+use crate::Document;
+
+pub mod builtin;
+pub use builtin::builtin_shortcodes;
+
+/// One use of a shortcode in a document:
+/// `\tag[key=value, other="quoted value"]{arg}{arg}`, followed for block
+/// shortcodes by a body ending with `\endtag`.
+#[derive(Clone, Debug, Default)]
+pub struct Call {
+    pub tag: String,
+    pub args: Vec<String>,
+    pub kwargs: HashMap<String, String>,
+    /// The enclosed text of a block shortcode, with its own shortcodes
+    /// already expanded.
+    pub body: Option<String>,
+}
+
+impl Call {
+    pub fn arg(&self, i: usize) -> Option<&str> {
+        self.args.get(i).map(String::as_str)
+    }
+
+    pub fn kwarg(&self, key: &str) -> Option<&str> {
+        self.kwargs.get(key).map(String::as_str)
+    }
+}
+
+/// What a shortcode renders to, which decides when its output is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Markdown, to be compiled along with the rest of the page.
+    Markdown,
+    /// Raw HTML, which must come out of the Markdown compiler unchanged.
+    Html,
+}
+
+pub trait Shortcode: Send + Sync {
+    /// The tag name (e.g. "note" for `\note`).
+    fn tag(&self) -> &str;
+
+    /// Whether the shortcode encloses a body terminated by `\end<tag>`.
+    fn block(&self) -> bool {
+        false
+    }
+
+    /// Whether `render` produces Markdown or HTML. Defaults to HTML.
+    fn output(&self) -> Output {
+        Output::Html
+    }
+
+    /// Render one call, usually to HTML. `doc` is the page being expanded.
+    fn render(&self, call: &Call, doc: &Document) -> Result<String>;
+
+    /// For shortcodes that pull in another source file, like `\include`:
+    /// the path of that file. Its shortcodes are then expanded in turn,
+    /// with errors reported against it.
+    fn source_path(&self, _call: &Call, _doc: &Document) -> Option<String> {
+        None
+    }
+}
+
+/// Declare that a shortcode produces Markdown rather than HTML.
+pub fn markdown(shortcode: Box<dyn Shortcode>) -> Box<dyn Shortcode> {
+    Box::new(MarkdownShortcode(shortcode))
+}
+
+struct MarkdownShortcode(Box<dyn Shortcode>);
+
+impl Shortcode for MarkdownShortcode {
+    fn tag(&self) -> &str {
+        self.0.tag()
+    }
+
+    fn block(&self) -> bool {
+        self.0.block()
+    }
+
+    fn output(&self) -> Output {
+        Output::Markdown
+    }
+
+    fn render(&self, call: &Call, doc: &Document) -> Result<String> {
+        self.0.render(call, doc)
+    }
+
+    fn source_path(&self, call: &Call, doc: &Document) -> Option<String> {
+        self.0.source_path(call, doc)
+    }
+}
+
+pub type Shortcodes = Vec<Box<dyn Shortcode>>;
+
+/// A shortcode backed by a closure, which may capture state.
+pub struct FnShortcode<F> {
+    tag: String,
+    block: bool,
+    render: F,
+}
+
+impl<F> Shortcode for FnShortcode<F>
+where
+    F: Fn(&Call, &Document) -> Result<String> + Send + Sync,
+{
+    fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    fn block(&self) -> bool {
+        self.block
+    }
+
+    fn render(&self, call: &Call, doc: &Document) -> Result<String> {
+        (self.render)(call, doc)
+    }
+}
+
+pub fn shortcode<F>(tag: &str, render: F) -> Box<dyn Shortcode>
+where
+    F: Fn(&Call, &Document) -> Result<String> + Send + Sync + 'static,
+{
+    Box::new(FnShortcode {
+        tag: tag.to_string(),
+        block: false,
+        render,
+    })
+}
+
+/// Like `shortcode`, for shortcodes with a body: `\tag{...} body \endtag`.
+pub fn block_shortcode<F>(tag: &str, render: F) -> Box<dyn Shortcode>
+where
+    F: Fn(&Call, &Document) -> Result<String> + Send + Sync + 'static,
+{
+    Box::new(FnShortcode {
+        tag: tag.to_string(),
+        block: true,
+        render,
+    })
+}
+
+/// A shortcode rendered from a Tera template, so that it can be added
+/// without recompiling the site.
+///
+/// The template sees the positional arguments as `args` (a list), the named
+/// arguments both as `kwargs` and as top-level variables, the expanded body
+/// of a block shortcode as `body`, and the page's front matter as `page`.
+pub struct TemplateShortcode {
+    tag: String,
+    block: bool,
+    output: Output,
+    tera: Tera,
+}
+
+impl TemplateShortcode {
+    pub fn new(tag: &str, block: bool, output: Output, source: &str) -> Result<Self> {
+        let mut tera = Tera::default();
+        // Arguments and bodies are written by the page's author, like the
+        // page itself, so they are not escaped.
+        tera.autoescape_on(vec![]);
+        tera.add_raw_template(tag, source)?;
+        Ok(TemplateShortcode {
+            tag: tag.to_string(),
+            block,
+            output,
+            tera,
+        })
+    }
+}
+
+impl Shortcode for TemplateShortcode {
+    fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    fn block(&self) -> bool {
+        self.block
+    }
+
+    fn output(&self) -> Output {
+        self.output
+    }
+
+    fn render(&self, call: &Call, doc: &Document) -> Result<String> {
+        let mut ctx = Context::new();
+        for (k, v) in &call.kwargs {
+            ctx.insert(k, v);
+        }
+        ctx.insert("args", &call.args);
+        ctx.insert("kwargs", &call.kwargs);
+        ctx.insert("body", &call.body.clone().unwrap_or_default());
+        ctx.insert("page", &doc.metadata);
+        ctx.insert("path", &doc.path);
+        // Template files end with a newline, which would break inline uses
+        let html = self.tera.render(&self.tag, &ctx)?;
+        Ok(html.trim_end_matches('\n').to_string())
+    }
+}
+
+/// Load every template in `dir` as a shortcode named after the file:
+/// `youtube.html` defines `\youtube`, and `note.block.html` defines the
+/// block shortcode `\note ... \endnote`. Templates with an `.md` extension
+/// produce Markdown, the others HTML. Hidden files and files with other
+/// extensions, such as editor backups, are ignored.
+pub fn template_shortcodes(dir: &str) -> Result<Shortcodes> {
+    let mut entries = fs::read_dir(Path::new(dir))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut handlers: Shortcodes = vec![];
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let output = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("md") => Output::Markdown,
+            Some("html") => Output::Html,
+            _ => continue,
+        };
+        if name.starts_with('.') || !path.is_file() {
+            continue;
+        }
+        let stem = match name.split_once('.') {
+            Some((stem, _)) => stem,
+            None => &name,
+        };
+        if stem.is_empty() || !stem.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(anyhow!(
+                "{}: shortcode names are made of letters, digits and `_`",
+                path.display()
+            ));
+        }
+        if handlers.iter().any(|h| h.tag() == stem) {
+            return Err(anyhow!(
+                "{}: shortcode `{}` is defined by more than one file",
+                path.display(),
+                stem
+            ));
+        }
+        let block = name.contains(".block.");
+        let source = fs::read_to_string(&path)?;
+        handlers.push(Box::new(TemplateShortcode::new(stem, block, output, &source)?));
+    }
+    Ok(handlers)
+}
+
+/// Expand every shortcode in `input`.
+///
+/// A shortcode is a backslash followed by a registered tag and a word
+/// boundary, so `\notebox` does not trigger `note`. `\\note` produces a
+/// literal `\note`. Fenced code blocks and inline code spans are copied
+/// untouched. Malformed calls are reported with their line and column.
+pub fn expand_shortcodes(
+    input: &str,
+    handlers: &[Box<dyn Shortcode>],
+    doc: &Document,
+) -> Result<String> {
+    let expander = Expander {
+        chars: input.chars().collect(),
+        handlers,
+        doc,
+        protected: None,
+        depth: 0,
+    };
+    let mut pos = 0;
+    expander.expand_until(&mut pos, None)
+}
+
+/// Expand shortcodes ahead of a Markdown compiler.
+///
+/// Markdown output is inlined as usual, while HTML output is replaced by a
+/// placeholder word that the compiler passes through. The returned HTML
+/// fragments are put back with `restore_shortcodes` once the page is
+/// compiled. `\\note` is left escaped, for the compiler to unescape.
+pub fn protect_shortcodes(
+    input: &str,
+    handlers: &[Box<dyn Shortcode>],
+    doc: &Document,
+) -> Result<(String, Vec<String>)> {
+    let protected = RefCell::new(vec![]);
+    let expander = Expander {
+        chars: input.chars().collect(),
+        handlers,
+        doc,
+        protected: Some(&protected),
+        depth: 0,
+    };
+    let mut pos = 0;
+    let output = expander.expand_until(&mut pos, None)?;
+    Ok((output, protected.into_inner()))
+}
+
+/// Replace the placeholders left by `protect_shortcodes` with their HTML.
+pub fn restore_shortcodes(html: &str, protected: &[String]) -> String {
+    let mut html = html.to_string();
+    // Later fragments may contain earlier ones, when shortcodes are nested
+    for (i, fragment) in protected.iter().enumerate().rev() {
+        let token = placeholder(i);
+        // A placeholder alone on a line comes back wrapped in a paragraph
+        html = html
+            .replace(&format!("<p>{}</p>", token), fragment)
+            .replace(&token, fragment);
+    }
+    html
+}
+
+fn placeholder(i: usize) -> String {
+    format!("AVDOUSHORTCODE{}END", i)
+}
+
+struct Expander<'a> {
+    chars: Vec<char>,
+    handlers: &'a [Box<dyn Shortcode>],
+    doc: &'a Document,
+    /// HTML set aside behind placeholders, when expanding before a Markdown
+    /// compiler.
+    protected: Option<&'a RefCell<Vec<String>>>,
+    /// How many included files deep this expansion is.
+    depth: usize,
+}
+
+/// Included files may include others, but not endlessly.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+impl Expander<'_> {
+    fn handler(&self, tag: &str) -> Option<&dyn Shortcode> {
+        if tag.is_empty() {
+            return None;
+        }
+        self.handlers
+            .iter()
+            .find(|h| h.tag() == tag)
+            .map(|h| h.as_ref())
+    }
+
+    fn is_end_tag(&self, tag: &str) -> bool {
+        tag.strip_prefix("end")
+            .and_then(|t| self.handler(t))
+            .map(|h| h.block())
+            .unwrap_or(false)
+    }
+
+    /// Expand from `pos` up to the end of input, or up to and including
+    /// `\<end>` when given.
+    fn expand_until(&self, pos: &mut usize, end: Option<(&str, usize)>) -> Result<String> {
+        let chars = &self.chars;
+        let mut output = String::new();
+
+        while *pos < chars.len() {
+            let c = chars[*pos];
+
+            if self.at_line_start(*pos)
+                && let Some(fence_end) = self.fenced_block(*pos)
+            {
+                output.extend(&chars[*pos..fence_end]);
+                *pos = fence_end;
+                continue;
+            }
+
+            if c == '`' {
+                let span_end = self.code_span(*pos);
+                output.extend(&chars[*pos..span_end]);
+                *pos = span_end;
+                continue;
             }
 
-            if let Some(handler) = matched {
-                // parse args
-                let mut args = Vec::new();
-                let mut pos = i + 1 + handler.tag.chars().count();
-                while pos < chars.len() && chars[pos] == '{' {
-                    let (arg, new_pos) = parse_braced(&chars, pos).unwrap();
-                    args.push(arg);
-                    pos = new_pos;
+            if c == '\\' {
+                // `\\tag` escapes a shortcode
+                if chars.get(*pos + 1) == Some(&'\\') {
+                    let tag = self.ident(*pos + 2);
+                    if self.handler(&tag).is_some() || self.is_end_tag(&tag) {
+                        // Pandoc would read a bare `\tag` as raw TeX and drop it
+                        if self.protected.is_some() {
+                            output.push('\\');
+                        }
+                        output.push('\\');
+                        output.push_str(&tag);
+                        *pos += 2 + tag.chars().count();
+                    } else {
+                        output.push_str("\\\\");
+                        *pos += 2;
+                    }
+                    continue;
                 }
 
-                output.push_str(&(handler.render)(&args));
-                i = pos;
-                continue;
+                let tag = self.ident(*pos + 1);
+                if let Some((end_tag, _)) = end
+                    && tag == end_tag
+                {
+                    *pos += 1 + tag.chars().count();
+                    return Ok(output);
+                }
+                if let Some(handler) = self.handler(&tag) {
+                    output.push_str(&self.expand_call(pos, handler)?);
+                    continue;
+                }
+                if self.is_end_tag(&tag) {
+                    return Err(self.error(*pos, &format!("unexpected \\{}", tag)));
+                }
             }
+
+            output.push(c);
+            *pos += 1;
         }
 
-        output.push(chars[i]);
-        i += 1;
+        match end {
+            Some((end_tag, opened)) => Err(self.error(
+                opened,
+                &format!("missing \\{} for this shortcode", end_tag),
+            )),
+            None => Ok(output),
+        }
     }
 
-    output
-}
+    fn expand_call(&self, pos: &mut usize, handler: &dyn Shortcode) -> Result<String> {
+        let chars = &self.chars;
+        let start = *pos;
+        let mut call = Call {
+            tag: handler.tag().to_string(),
+            ..Call::default()
+        };
+        *pos += 1 + handler.tag().chars().count();
+
+        // named arguments
+        if chars.get(*pos) == Some(&'[') {
+            *pos = self.parse_bracketed(*pos, &mut call)?;
+        }
+
+        // positional arguments
+        while chars.get(*pos) == Some(&'{') {
+            let (arg, new_pos) = self.parse_braced(*pos)?;
+            call.args.push(arg);
+            *pos = new_pos;
+        }
+
+        // the body, expanded in turn
+        if handler.block() {
+            let end_tag = format!("end{}", handler.tag());
+            call.body = Some(self.expand_until(pos, Some((&end_tag, start)))?);
+        }
+
+        let mut output = handler
+            .render(&call, self.doc)
+            .map_err(|e| self.error(start, &format!("\\{}: {}", handler.tag(), e)))?;
+        if let Some(path) = handler.source_path(&call, self.doc) {
+            output = self
+                .expand_included(&output, path)
+                .map_err(|e| self.error(start, &format!("\\{}: {}", handler.tag(), e)))?;
+        }
+
+        match &self.protected {
+            Some(protected) if handler.output() == Output::Html => {
+                let mut protected = protected.borrow_mut();
+                protected.push(output);
+                Ok(placeholder(protected.len() - 1))
+            }
+            _ => Ok(output),
+        }
+    }
+
+    /// Expand the shortcodes of a file pulled in by the current document.
+    fn expand_included(&self, source: &str, path: String) -> Result<String> {
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(anyhow!("includes nested too deeply"));
+        }
+        let doc = Document {
+            path,
+            content: String::new(),
+            metadata: self.doc.metadata.clone(),
+        };
+        let expander = Expander {
+            chars: source.chars().collect(),
+            handlers: self.handlers,
+            doc: &doc,
+            protected: self.protected,
+            depth: self.depth + 1,
+        };
+        let mut pos = 0;
+        expander.expand_until(&mut pos, None)
+    }
+
+    /// The identifier (letters, digits, `_`) starting at `pos`.
+    fn ident(&self, pos: usize) -> String {
+        self.chars
+            .iter()
+            .skip(pos)
+            .take_while(|c| c.is_alphanumeric() || **c == '_')
+            .collect()
+    }
+
+    fn parse_braced(&self, start: usize) -> Result<(String, usize)> {
+        let mut depth = 0;
+        let mut content = String::new();
 
-fn parse_braced(input: &[char], start: usize) -> Result<(String, usize)> {
-    if input.get(start) != Some(&'{') {
-        return Err(anyhow::anyhow!("Expected opening brace"));
+        for (i, &c) in self.chars[start..].iter().enumerate() {
+            match c {
+                '{' if depth == 0 => depth = 1, // first opening brace, don’t push
+                '{' => {
+                    depth += 1;
+                    content.push(c);
+                }
+                '}' if depth == 1 => return Ok((content, start + i + 1)),
+                '}' if depth > 1 => {
+                    depth -= 1;
+                    content.push(c);
+                }
+                _ => content.push(c),
+            }
+        }
+
+        Err(self.error(start, "unbalanced braces in shortcode argument"))
+    }
+
+    /// Parse `[key=value, "positional", key2="quoted, with comma"]` into
+    /// `call`. Items without a key are positional arguments.
+    fn parse_bracketed(&self, start: usize, call: &mut Call) -> Result<usize> {
+        let mut key: Option<String> = None;
+        let mut current = String::new();
+        let mut quoted = false;
+
+        for (i, &c) in self.chars[start..].iter().enumerate().skip(1) {
+            match c {
+                '"' => quoted = !quoted,
+                '=' if !quoted && key.is_none() => {
+                    key = Some(current.trim().to_string());
+                    current.clear();
+                }
+                ',' | ']' if !quoted => {
+                    let value = current.trim().to_string();
+                    match key.take() {
+                        Some(key) => {
+                            call.kwargs.insert(key, value);
+                        }
+                        None if !value.is_empty() => call.args.push(value),
+                        None => {}
+                    }
+                    current.clear();
+                    if c == ']' {
+                        return Ok(start + i + 1);
+                    }
+                }
+                _ => current.push(c),
+            }
+        }
+
+        Err(self.error(start, "unclosed '[' in shortcode arguments"))
     }
 
-    let mut depth = 0;
-    let mut content = String::new();
+    fn at_line_start(&self, pos: usize) -> bool {
+        pos == 0 || self.chars[pos - 1] == '\n'
+    }
 
-    for (i, &c) in input[start..].iter().enumerate() {
-        match c {
-            '{' if depth == 0 => depth = 1, // first opening brace, don’t push
-            '{' => {
-                depth += 1;
-                content.push(c);
+    /// If a fenced code block (``` or ~~~) opens on the line starting at
+    /// `pos`, the position just after its closing fence (or end of input).
+    /// As in CommonMark, a closing fence carries no info string.
+    fn fenced_block(&self, pos: usize) -> Option<usize> {
+        let (fence, len, _) = self.fence_at(pos)?;
+        let mut line = self.next_line(pos);
+        while line < self.chars.len() {
+            let next = self.next_line(line);
+            if let Some((c, n, end)) = self.fence_at(line)
+                && c == fence
+                && n >= len
+                && self.chars[end..next].iter().all(|c| c.is_whitespace())
+            {
+                return Some(next);
             }
-            '}' if depth == 1 => return Ok((content, start + i + 1)),
-            '}' if depth > 1 => {
-                depth -= 1;
-                content.push(c);
+            line = next;
+        }
+        Some(self.chars.len())
+    }
+
+    /// Fence character and length on the line starting at `pos`, allowing up
+    /// to three spaces of indentation, and the position just after it.
+    fn fence_at(&self, pos: usize) -> Option<(char, usize, usize)> {
+        let chars = &self.chars;
+        let mut i = pos;
+        while i < chars.len() && i - pos < 3 && chars[i] == ' ' {
+            i += 1;
+        }
+        let fence = *chars.get(i).filter(|c| **c == '`' || **c == '~')?;
+        let len = chars[i..].iter().take_while(|c| **c == fence).count();
+        (len >= 3).then_some((fence, len, i + len))
+    }
+
+    fn next_line(&self, pos: usize) -> usize {
+        match self.chars[pos..].iter().position(|c| *c == '\n') {
+            Some(i) => pos + i + 1,
+            None => self.chars.len(),
+        }
+    }
+
+    /// The end of the inline code span opened by the backticks at `pos`, or
+    /// just past the backticks if it is never closed.
+    fn code_span(&self, pos: usize) -> usize {
+        let chars = &self.chars;
+        let len = chars[pos..].iter().take_while(|c| **c == '`').count();
+        let mut i = pos + len;
+        while i < chars.len() {
+            if chars[i] == '`' {
+                let run = chars[i..].iter().take_while(|c| **c == '`').count();
+                if run == len {
+                    return i + run;
+                }
+                i += run;
+            } else {
+                i += 1;
             }
-            _ => content.push(c),
         }
+        pos + len
     }
 
-    Err(anyhow::anyhow!("Unbalanced braces"))
+    fn error(&self, pos: usize, msg: &str) -> anyhow::Error {
+        let before = &self.chars[..pos.min(self.chars.len())];
+        let line = before.iter().filter(|c| **c == '\n').count() + 1;
+        let col = before.iter().rev().take_while(|c| **c != '\n').count() + 1;
+        anyhow!("{}:{}:{}: {}", self.doc.path, line, col, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> Document {
+        Document {
+            path: "test.md".to_string(),
+            content: String::new(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    fn handlers() -> Shortcodes {
+        vec![
+            shortcode("note", |call: &Call, _doc: &Document| {
+                Ok(format!("<note {}>", call.args.join("|")))
+            }),
+            shortcode("kw", |call: &Call, _doc: &Document| {
+                let mut kwargs: Vec<_> = call.kwargs.iter().collect();
+                kwargs.sort();
+                Ok(format!("{:?} {:?}", call.args, kwargs))
+            }),
+            block_shortcode("box", |call: &Call, _doc: &Document| {
+                Ok(format!("[{}]", call.body.as_deref().unwrap_or("")))
+            }),
+            markdown(shortcode("md", |_call: &Call, _doc: &Document| {
+                Ok("*md*".to_string())
+            })),
+        ]
+    }
+
+    fn expand(input: &str) -> Result<String> {
+        expand_shortcodes(input, &handlers(), &doc())
+    }
+
+    #[test]
+    fn expands_arguments() {
+        assert_eq!(expand(r"a \note{x}{y} b").unwrap(), "a <note x|y> b");
+        assert_eq!(expand(r"\note").unwrap(), "<note >");
+        assert_eq!(
+            expand(r"\note{a {nested} b}").unwrap(),
+            "<note a {nested} b>"
+        );
+    }
+
+    #[test]
+    fn parses_named_arguments() {
+        assert_eq!(
+            expand(r#"\kw[pos, a=1, b="x, y"]{z}"#).unwrap(),
+            r#"["pos", "z"] [("a", "1"), ("b", "x, y")]"#
+        );
+    }
+
+    #[test]
+    fn requires_a_word_boundary() {
+        assert_eq!(expand(r"\notebox \note_x").unwrap(), r"\notebox \note_x");
+        assert_eq!(expand(r"\note.").unwrap(), "<note >.");
+    }
+
+    #[test]
+    fn double_backslash_escapes_shortcodes() {
+        assert_eq!(expand(r"\\note{x}").unwrap(), r"\note{x}");
+        assert_eq!(expand(r"\\endbox").unwrap(), r"\endbox");
+        assert_eq!(expand(r"a\\b").unwrap(), r"a\\b");
+    }
+
+    #[test]
+    fn leaves_other_escapes_alone() {
+        assert_eq!(
+            expand(r"use \* and \_ here").unwrap(),
+            r"use \* and \_ here"
+        );
+    }
+
+    #[test]
+    fn never_matches_an_empty_tag() {
+        let handlers = vec![shortcode("", |_call: &Call, _doc: &Document| {
+            Ok("EMPTY".to_string())
+        })];
+        assert_eq!(
+            expand_shortcodes(r"use \* here", &handlers, &doc()).unwrap(),
+            r"use \* here"
+        );
+    }
+
+    #[test]
+    fn expands_block_bodies() {
+        assert_eq!(
+            expand(r"\box hello \note{x} \endbox!").unwrap(),
+            "[ hello <note x> ]!"
+        );
+        assert_eq!(
+            expand(r"\box a \box b \endbox c \endbox").unwrap(),
+            "[ a [ b ] c ]"
+        );
+    }
+
+    #[test]
+    fn skips_code() {
+        let input = "```\n\\note\n```\n`\\note` ``a ` \\note`` \\note";
+        assert_eq!(
+            expand(input).unwrap(),
+            "```\n\\note\n```\n`\\note` ``a ` \\note`` <note >"
+        );
+        assert_eq!(
+            expand("~~~~\n\\note\n~~~\n\\note").unwrap(),
+            "~~~~\n\\note\n~~~\n\\note"
+        );
+        assert_eq!(expand("`unclosed \\note").unwrap(), "`unclosed <note >");
+    }
+
+    #[test]
+    fn closing_fences_have_no_info_string() {
+        let input = "```rust\n\\note\n```rust\n\\note\n```  \n\\note";
+        assert_eq!(
+            expand(input).unwrap(),
+            "```rust\n\\note\n```rust\n\\note\n```  \n<note >"
+        );
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let err = expand("first\n  \\note{x").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.md:2:8: unbalanced braces in shortcode argument"
+        );
+
+        let err = expand("\\box\nbody").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.md:1:1: missing \\endbox for this shortcode"
+        );
+
+        let err = expand("a \\endbox").unwrap_err();
+        assert_eq!(err.to_string(), "test.md:1:3: unexpected \\endbox");
+
+        let err = expand("\\kw[a=1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.md:1:4: unclosed '[' in shortcode arguments"
+        );
+    }
+
+    #[test]
+    fn protects_html_output() {
+        let (text, protected) =
+            protect_shortcodes("\\note{x}\n\n\\md", &handlers(), &doc()).unwrap();
+        assert_eq!(text, format!("{}\n\n*md*", placeholder(0)));
+        assert_eq!(protected, vec!["<note x>".to_string()]);
+
+        let html = format!("<p>{}</p>\n<p><em>md</em></p>", placeholder(0));
+        assert_eq!(
+            restore_shortcodes(&html, &protected),
+            "<note x>\n<p><em>md</em></p>"
+        );
+    }
+
+    #[test]
+    fn protect_keeps_escapes_for_the_compiler() {
+        let (text, protected) =
+            protect_shortcodes(r"\\note{x} \\endbox a\\b", &handlers(), &doc()).unwrap();
+        assert_eq!(text, r"\\note{x} \\endbox a\\b");
+        assert!(protected.is_empty());
+    }
+
+    #[test]
+    fn expands_included_files() {
+        let dir = std::env::temp_dir().join(format!("avdou-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("snippets")).unwrap();
+        fs::write(dir.join("snippets/more.md"), "\n\\note{z").unwrap();
+        fs::write(dir.join("snippets/loop.md"), "\\include{loop.md}").unwrap();
+
+        let mut handlers = handlers();
+        handlers.extend(builtin_shortcodes(
+            &crate::route::Links::new(),
+            &crate::images::ImageSets::new(),
+        ));
+        let page = Document {
+            path: dir.join("page.md").to_string_lossy().into_owned(),
+            ..doc()
+        };
+        let license = dir.join("snippets/license.md");
+        fs::write(&license, "---\ntitle: x\n---\nSee \\note{y}.").unwrap();
+        assert_eq!(
+            expand_shortcodes("\\include{snippets/license.md}", &handlers, &page).unwrap(),
+            "See <note y>."
+        );
+
+        fs::write(&license, "See \\note{y}.\n\\include{more.md}").unwrap();
+        let err = expand_shortcodes("x \\include{snippets/license.md}", &handlers, &page)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!(
+                "{}:1:3: \\include: {}:2:1: \\include: {}:2:6: unbalanced braces in shortcode argument",
+                page.path,
+                license.display(),
+                dir.join("snippets/more.md").display()
+            )
+        );
+
+        let err = expand_shortcodes("\\include{snippets/loop.md}", &handlers, &page)
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("includes nested too deeply"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_template_shortcodes() {
+        let dir = std::env::temp_dir().join(format!("avdou-shortcodes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hi.html"), "<b>{{ args.0 }}</b>\n").unwrap();
+        fs::write(dir.join("aside.block.md"), "> {{ body }}").unwrap();
+        fs::write(dir.join(".hidden"), "HIDDEN").unwrap();
+        fs::write(dir.join("hi.html.swp"), "SWAP").unwrap();
+
+        let handlers = template_shortcodes(&dir.to_string_lossy()).unwrap();
+        let mut tags: Vec<_> = handlers.iter().map(|h| h.tag()).collect();
+        tags.sort();
+        assert_eq!(tags, ["aside", "hi"]);
+        assert_eq!(
+            expand_shortcodes(r"\hi{x} \* \aside y\endaside", &handlers, &doc()).unwrap(),
+            r"<b>x</b> \* >  y"
+        );
+
+        fs::write(dir.join("hi.md"), "again").unwrap();
+        assert!(template_shortcodes(&dir.to_string_lossy()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,188 @@
+//! Shortcodes most sites need, registered together with
+//! `builtin_shortcodes`.
+//!
+//! ```text
+//! \figure[alt="A cat"]{img/cat.jpg}{The cat, asleep}
+//! \youtube{dQw4w9WgXcQ}   \vimeo{76979871}   \video{media/demo.mp4}
+//! \callout[kind=warning, title="Careful"] Markdown body \endcallout
+//! \include{snippets/license.md}
+//! [the other post](\ref{posts/other.md})
+//! ```
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{block_shortcode, markdown, shortcode, Call, Output, Shortcode, Shortcodes};
+use crate::document::parse_front_matter;
+use crate::images::ImageSets;
+use crate::redirects::escape;
+use crate::route::Links;
+use crate::Document;
+
+/// All the built-in shortcodes. `links` is the site's table of published
+/// URLs (`Site::links`), used by `\ref`, and `images` its table of
+/// responsive images (`Site::image_sets`), used by `\figure`.
+pub fn builtin_shortcodes(links: &Links, images: &ImageSets) -> Shortcodes {
+    let links = links.clone();
+    let images = images.clone();
+    vec![
+        shortcode("figure", move |call: &Call, doc: &Document| {
+            figure(call, doc, &images)
+        }),
+        shortcode("youtube", youtube),
+        shortcode("vimeo", vimeo),
+        shortcode("video", video),
+        markdown(block_shortcode("callout", callout)),
+        Box::new(Include),
+        markdown(shortcode("ref", move |call: &Call, _doc: &Document| {
+            let target = required(call, 0, "the source path of a page")?;
+            links
+                .get(target)
+                .ok_or_else(|| anyhow::anyhow!("no page is published from '{}'", target))
+        })),
+    ]
+}
+
+/// `\figure[alt=..., class=..., sizes=...]{src}{caption}`. Images published
+/// by an `Images` rule come with their variants, for the browser to pick
+/// from according to `sizes` (`100vw` by default).
+fn figure(call: &Call, _doc: &Document, images: &ImageSets) -> Result<String> {
+    let src = required(call, 0, "an image path")?;
+    let caption = call.arg(1).unwrap_or("");
+    let alt = call.kwarg("alt").unwrap_or(caption);
+    let class = call.kwarg("class").unwrap_or("figure");
+
+    let mut html = format!(r#"<figure class="{}">"#, escape(class));
+    match images.get(src) {
+        Some(set) => {
+            let sizes = escape(call.kwarg("sizes").unwrap_or("100vw"));
+            let (fallback, others) = set.sources.split_last().unwrap();
+            if !others.is_empty() {
+                html.push_str("<picture>");
+            }
+            for source in others {
+                html.push_str(&format!(
+                    r#"<source type="{}" srcset="{}" sizes="{}">"#,
+                    source.mime_type,
+                    escape(&source.srcset),
+                    sizes
+                ));
+            }
+            html.push_str(&format!(
+                r#"<img src="{}" srcset="{}" sizes="{}" width="{}" height="{}" alt="{}" loading="lazy" style="max-width:100%;height:auto">"#,
+                escape(&set.src),
+                escape(&fallback.srcset),
+                sizes,
+                set.width,
+                set.height,
+                escape(alt)
+            ));
+            if !others.is_empty() {
+                html.push_str("</picture>");
+            }
+        }
+        None => html.push_str(&format!(
+            r#"<img src="{}" alt="{}" loading="lazy" style="max-width:100%;height:auto">"#,
+            escape(src),
+            escape(alt)
+        )),
+    }
+    if !caption.is_empty() {
+        html.push_str(&format!("<figcaption>{}</figcaption>", caption));
+    }
+    html.push_str("</figure>");
+    Ok(html)
+}
+
+/// `\youtube{id}`, using the no-cookie domain.
+fn youtube(call: &Call, _doc: &Document) -> Result<String> {
+    let id = required(call, 0, "a video id")?;
+    Ok(embed(&format!(
+        "https://www.youtube-nocookie.com/embed/{}",
+        escape(id)
+    )))
+}
+
+/// `\vimeo{id}`
+fn vimeo(call: &Call, _doc: &Document) -> Result<String> {
+    let id = required(call, 0, "a video id")?;
+    Ok(embed(&format!("https://player.vimeo.com/video/{}", escape(id))))
+}
+
+/// `\video[poster=...]{src}` for self-hosted files.
+fn video(call: &Call, _doc: &Document) -> Result<String> {
+    let src = required(call, 0, "a video path")?;
+    let poster = call
+        .kwarg("poster")
+        .map(|p| format!(r#" poster="{}""#, escape(p)))
+        .unwrap_or_default();
+    Ok(format!(
+        r#"<video controls preload="metadata"{} style="max-width:100%;height:auto"><source src="{}"></video>"#,
+        poster,
+        escape(src)
+    ))
+}
+
+/// 16:9 iframe that scales with its container.
+fn embed(src: &str) -> String {
+    format!(
+        r#"<div class="embed" style="position:relative;padding-bottom:56.25%;height:0;overflow:hidden"><iframe src="{}" style="position:absolute;top:0;left:0;width:100%;height:100%;border:0" allow="fullscreen; picture-in-picture" loading="lazy"></iframe></div>"#,
+        src
+    )
+}
+
+/// `\callout[kind=note|tip|warning|danger, title=...] body \endcallout`
+///
+/// Produces a pandoc fenced div, so the body is compiled as Markdown.
+fn callout(call: &Call, _doc: &Document) -> Result<String> {
+    let kind = call.kwarg("kind").or(call.arg(0)).unwrap_or("note");
+    let mut md = format!("\n\n::: {{.callout .callout-{}}}\n", kind);
+    if let Some(title) = call.kwarg("title") {
+        md.push_str(&format!("**{}**\n\n", title));
+    }
+    md.push_str(call.body.as_deref().unwrap_or("").trim());
+    md.push_str("\n:::\n\n");
+    Ok(md)
+}
+
+/// `\include{path}`: the body of another source file, relative to the
+/// current document, without its front matter. Shortcodes in it are
+/// expanded too.
+struct Include;
+
+impl Include {
+    fn path(call: &Call, doc: &Document) -> Result<PathBuf> {
+        let rel = required(call, 0, "a file path")?;
+        let dir = Path::new(&doc.path).parent().unwrap_or(Path::new(""));
+        Ok(dir.join(rel))
+    }
+}
+
+impl Shortcode for Include {
+    fn tag(&self) -> &str {
+        "include"
+    }
+
+    fn output(&self) -> Output {
+        Output::Markdown
+    }
+
+    fn render(&self, call: &Call, doc: &Document) -> Result<String> {
+        let path = Include::path(call, doc)?;
+        let raw = fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("cannot include {}: {}", path.display(), e))?;
+        let (_, body) = parse_front_matter(&raw);
+        Ok(body)
+    }
+
+    fn source_path(&self, call: &Call, doc: &Document) -> Option<String> {
+        let path = Include::path(call, doc).ok()?;
+        Some(path.to_string_lossy().into_owned())
+    }
+}
+
+fn required<'a>(call: &'a Call, i: usize, what: &str) -> Result<&'a str> {
+    call.arg(i)
+        .ok_or_else(|| anyhow::anyhow!("expected {} as argument {}", what, i + 1))
+}